@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A stub age plugin for testing plugin support without hardware keys.
+//!
+//! It "wraps" file keys by storing them as they are, so never use it for real
+//! secrets. Put it into `$PATH` as `age-plugin-stub` and use:
+//! - recipient: `age1stub1wd682c3qwfjkx6tsd9jkuaqewdtx9`
+//! - identity:  `AGE-PLUGIN-STUB-1WD682C3QD9JX2MN5D968JY53S0C`
+
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+use std::io::{self, BufRead, Write};
+
+const STANZA_TYPE: &str = "stub";
+const COLUMNS: usize = 64;
+
+struct Command {
+    tag: String,
+    args: Vec<String>,
+    body: Vec<u8>,
+}
+
+fn main() -> io::Result<()> {
+    let state_machine = std::env::args()
+        .find_map(|arg| arg.strip_prefix("--age-plugin=").map(String::from))
+        .ok_or_else(|| invalid("usage: age-plugin-stub --age-plugin=<state machine>"))?;
+
+    let mut input = io::stdin().lock();
+    let mut output = io::stdout().lock();
+
+    match state_machine.as_str() {
+        "recipient-v1" => recipient_v1(&mut input, &mut output),
+        "identity-v1" => identity_v1(&mut input, &mut output),
+        other => Err(invalid(&format!("unknown state machine {}", other))),
+    }
+}
+
+fn recipient_v1(input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+    let mut file_keys = Vec::new();
+    for command in read_phase(input)? {
+        if command.tag == "wrap-file-key" {
+            file_keys.push(command.body);
+        }
+    }
+
+    for (index, file_key) in file_keys.iter().enumerate() {
+        let index = index.to_string();
+        write_command(output, "recipient-stanza", &[&index, STANZA_TYPE], file_key)?;
+        read_command(input)?;
+    }
+
+    write_command(output, "done", &[], &[])
+}
+
+fn identity_v1(input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+    let mut file_keys = Vec::new();
+    for command in read_phase(input)? {
+        let stanza_type = command.args.get(1).map(String::as_str);
+        if command.tag == "recipient-stanza" && stanza_type == Some(STANZA_TYPE) {
+            let index = command.args[0].clone();
+            if !file_keys.iter().any(|(i, _)| i == &index) {
+                file_keys.push((index, command.body));
+            }
+        }
+    }
+
+    for (index, file_key) in &file_keys {
+        write_command(output, "msg", &[], b"stub: unwrapping file key")?;
+        read_command(input)?;
+        write_command(output, "file-key", &[index], file_key)?;
+        read_command(input)?;
+    }
+
+    write_command(output, "done", &[], &[])
+}
+
+/// Reads the commands of the first phase, up to `done`.
+fn read_phase(input: &mut impl BufRead) -> io::Result<Vec<Command>> {
+    let mut commands = Vec::new();
+    loop {
+        let command = read_command(input)?;
+        if command.tag == "done" {
+            return Ok(commands);
+        }
+        commands.push(command);
+    }
+}
+
+fn read_command(input: &mut impl BufRead) -> io::Result<Command> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    let mut words = line
+        .trim_end()
+        .strip_prefix("-> ")
+        .ok_or_else(|| invalid("expected a command"))?
+        .split(' ')
+        .map(String::from);
+    let tag = words.next().unwrap_or_default();
+    let args = words.collect();
+
+    // The body is wrapped at 64 columns and ends with a shorter line.
+    let mut encoded = String::new();
+    loop {
+        let mut line = String::new();
+        input.read_line(&mut line)?;
+        let line = line.trim_end_matches('\n');
+        encoded.push_str(line);
+        if line.len() < COLUMNS {
+            break;
+        }
+    }
+
+    let body = BASE64_STANDARD_NO_PAD
+        .decode(encoded)
+        .map_err(|e| invalid(&e.to_string()))?;
+    Ok(Command { tag, args, body })
+}
+
+fn write_command(output: &mut impl Write, tag: &str, args: &[&str], body: &[u8]) -> io::Result<()> {
+    write!(output, "-> {}", tag)?;
+    for arg in args {
+        write!(output, " {}", arg)?;
+    }
+    writeln!(output)?;
+
+    let encoded = BASE64_STANDARD_NO_PAD.encode(body);
+    for line in encoded.as_bytes().chunks(COLUMNS) {
+        output.write_all(line)?;
+        writeln!(output)?;
+    }
+    if encoded.len().is_multiple_of(COLUMNS) {
+        writeln!(output)?;
+    }
+    output.flush()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
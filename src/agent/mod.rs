@@ -0,0 +1,305 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+mod protocol;
+
+#[cfg(test)]
+mod tests;
+
+use age::{secrecy::ExposeSecret, DecryptError, Identity};
+use age_core::format::{FileKey, Stanza};
+use anyhow::anyhow;
+use protocol::{Held, Request, Response};
+use std::{
+    env,
+    fs::{self, DirBuilder},
+    io::{BufReader, ErrorKind},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    debug,
+    file_io::{self, Unlocked},
+    info, warn, Result,
+};
+
+const SOCKET_ENV: &str = "BOXEDNIX_AGENT_SOCK";
+const SOCKET_FILE: &str = "agent.sock";
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The agent's socket: `$BOXEDNIX_AGENT_SOCK`, or a socket in a private
+/// directory below `$XDG_RUNTIME_DIR` (or the temp dir).
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = env::var_os(SOCKET_ENV) {
+        return PathBuf::from(path);
+    }
+
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("boxednix"),
+        None => env::temp_dir().join(format!("boxednix-{}", uid())),
+    };
+    dir.join(SOCKET_FILE)
+}
+
+/// Unlocks the identities and serves unwrap requests until `lifetime` has
+/// passed or the agent is stopped.
+pub fn serve(identities: Vec<PathBuf>, lifetime: Duration) -> Result<()> {
+    file_io::protect_process();
+
+    let mut unlocked = Vec::new();
+    for path in identities {
+        unlocked.push(Unlocked::read(&path, "The agent")?);
+    }
+    lock_keys(&unlocked);
+
+    let socket = socket_path();
+    let listener = bind(&socket)?;
+
+    println!("{}={}; export {};", SOCKET_ENV, socket.display(), SOCKET_ENV);
+    eprintln!(
+        "Unlocked identity files: {}, for {} minutes",
+        unlocked.len(),
+        lifetime.as_secs() / 60
+    );
+
+    // The listener blocks in accept, so the timer wakes it up with a connection.
+    let expired = Arc::new(AtomicBool::new(false));
+    {
+        let expired = expired.clone();
+        let socket = socket.clone();
+        thread::spawn(move || {
+            thread::sleep(lifetime);
+            expired.store(true, Ordering::SeqCst);
+            let _ = UnixStream::connect(socket);
+        });
+    }
+
+    for stream in listener.incoming() {
+        if expired.load(Ordering::SeqCst) {
+            info!("agent lifetime expired");
+            break;
+        }
+
+        match stream.map_err(Into::into).and_then(|stream| handle(stream, &unlocked)) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => warn!("agent request failed: {:?}", e),
+        }
+    }
+
+    drop(unlocked);
+    fs::remove_file(&socket)?;
+    Ok(())
+}
+
+/// Stops a running agent.
+pub fn stop() -> Result<()> {
+    match request(&socket_path(), &Request::Stop)? {
+        Response::Ok => Ok(()),
+        _ => Err(anyhow!("unexpected response from agent")),
+    }
+}
+
+/// Handles one connection. Returns false when the agent should stop.
+fn handle(stream: UnixStream, unlocked: &[Unlocked]) -> Result<bool> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut output = stream.try_clone()?;
+    let mut input = BufReader::new(stream);
+
+    let (response, keep_running) = match Request::read(&mut input)? {
+        Request::List => {
+            let held = unlocked
+                .iter()
+                .map(|unlocked| Held {
+                    path: unlocked.path.clone(),
+                    recipients: unlocked.recipients().to_vec(),
+                })
+                .collect();
+            (Response::Identities(held), true)
+        }
+        Request::Unwrap { identity, stanzas } => {
+            let response = match unlocked.iter().find(|u| u.path == identity) {
+                Some(unlocked) => match unwrap_stanzas(unlocked, &stanzas) {
+                    Some(Ok(file_key)) => Response::FileKey(file_key),
+                    Some(Err(e)) => Response::Error(e.to_string()),
+                    None => Response::NoMatch,
+                },
+                None => Response::Error(format!("identity {:?} is not held", identity)),
+            };
+            (response, true)
+        }
+        Request::Stop => (Response::Ok, false),
+    };
+
+    response.write(&mut output)?;
+    Ok(keep_running)
+}
+
+fn unwrap_stanzas(unlocked: &Unlocked, stanzas: &[Stanza]) -> Option<Result<FileKey, DecryptError>> {
+    unlocked
+        .identities()
+        .find_map(|identity| identity.unwrap_stanzas(stanzas))
+}
+
+/// Creates the socket in a directory only the user can access. A missing
+/// directory is created that way; an existing one is left as it is, and
+/// refused if others can access it.
+fn bind(socket: &Path) -> Result<UnixListener> {
+    let dir = socket
+        .parent()
+        .ok_or(anyhow!("invalid socket path {:?}", socket))?;
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+    let metadata = fs::metadata(dir)?;
+    if metadata.uid() != uid() {
+        return Err(anyhow!("{:?} is not owned by the current user", dir));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(anyhow!(
+            "{:?} can be accessed by other users, put ${} in a directory only you can access",
+            dir,
+            SOCKET_ENV
+        ));
+    }
+
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(anyhow!("An agent is already running at {:?}", socket));
+        }
+        fs::remove_file(socket)?;
+    }
+
+    let listener = UnixListener::bind(socket)?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Keeps the unlocked keys out of swap. Only their buffers are locked, as all
+/// locked memory counts against `RLIMIT_MEMLOCK`. Failing to do so is not
+/// fatal.
+fn lock_keys(unlocked: &[Unlocked]) {
+    let mut buffers: Vec<(*const u8, usize)> = Vec::new();
+    for unlocked in unlocked {
+        let content = unlocked.content().expose_secret();
+        buffers.push((content.as_ptr(), content.len()));
+        for identity in unlocked.identities() {
+            let key = (identity as *const dyn Identity).cast::<u8>();
+            buffers.push((key, std::mem::size_of_val(identity)));
+        }
+    }
+
+    for (buffer, len) in buffers.into_iter().filter(|(_, len)| *len > 0) {
+        // SAFETY: the buffers belong to `unlocked`, which outlives the call.
+        if unsafe { libc::mlock(buffer.cast(), len) } != 0 {
+            let message = format!("can't lock the keys: {}", std::io::Error::last_os_error());
+            warn!("{}", message);
+            eprintln!("warning: {}", message);
+            return;
+        }
+    }
+}
+
+fn uid() -> u32 {
+    // SAFETY: getuid always succeeds.
+    unsafe { libc::getuid() }
+}
+
+fn request(socket: &Path, request: &Request) -> Result<Response> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    request.write(&mut stream)?;
+    Response::read(&mut BufReader::new(stream))
+}
+
+/// A connection to a running agent.
+pub struct Agent {
+    socket: PathBuf,
+    held: Vec<Held>,
+}
+
+impl Agent {
+    /// Connects to the agent, if one is running.
+    pub fn connect() -> Option<Self> {
+        let socket = socket_path();
+        match request(&socket, &Request::List) {
+            Ok(Response::Identities(held)) => Some(Self { socket, held }),
+            Ok(_) => None,
+            Err(e) => {
+                if !matches!(e.downcast_ref::<std::io::Error>(), Some(e) if e.kind() == ErrorKind::NotFound)
+                {
+                    debug!("no agent at {:?}: {}", socket, e);
+                }
+                None
+            }
+        }
+    }
+
+    /// The recipients of an identity file, if the agent holds it.
+    pub fn recipients(&self, identity: &str) -> Option<&[String]> {
+        self.find(identity).map(|held| held.recipients.as_slice())
+    }
+
+    /// An identity that unwraps through the agent, if it holds the identity file.
+    pub fn identity(&self, identity: &str) -> Option<AgentIdentity> {
+        self.find(identity).map(|held| AgentIdentity {
+            socket: self.socket.clone(),
+            path: held.path.clone(),
+        })
+    }
+
+    fn find(&self, identity: &str) -> Option<&Held> {
+        let path = Path::new(identity).canonicalize().ok()?;
+        self.held.iter().find(|held| held.path == path)
+    }
+}
+
+pub struct AgentIdentity {
+    socket: PathBuf,
+    path: PathBuf,
+}
+
+impl Identity for AgentIdentity {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        self.unwrap_stanzas(std::slice::from_ref(stanza))
+    }
+
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, DecryptError>> {
+        let unwrap = Request::Unwrap {
+            identity: self.path.clone(),
+            stanzas: stanzas
+                .iter()
+                .map(|stanza| Stanza {
+                    tag: stanza.tag.clone(),
+                    args: stanza.args.clone(),
+                    body: stanza.body.clone(),
+                })
+                .collect(),
+        };
+
+        // The identity file is still tried directly if the agent fails.
+        match request(&self.socket, &unwrap) {
+            Ok(Response::FileKey(file_key)) => Some(Ok(file_key)),
+            Ok(Response::NoMatch) => None,
+            Ok(Response::Error(message)) => {
+                warn!("agent: {}", message);
+                None
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("agent: {:?}", e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The line based protocol between `bx agent` and other `bx` processes. A client
+//! sends one request per connection and reads one response.
+
+use age::secrecy::ExposeSecret;
+use age_core::format::{FileKey, Stanza};
+use anyhow::anyhow;
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+};
+
+use crate::Result;
+
+pub enum Request {
+    /// Lists the unlocked identities.
+    List,
+    /// Unwraps the file key with the identities of one identity file.
+    Unwrap {
+        identity: PathBuf,
+        stanzas: Vec<Stanza>,
+    },
+    /// Stops the agent.
+    Stop,
+}
+
+pub enum Response {
+    Identities(Vec<Held>),
+    FileKey(FileKey),
+    NoMatch,
+    Ok,
+    Error(String),
+}
+
+/// An identity file unlocked by the agent, with the recipients of its keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Held {
+    pub path: PathBuf,
+    pub recipients: Vec<String>,
+}
+
+impl Request {
+    pub fn write<W: Write>(&self, output: &mut W) -> Result<()> {
+        match self {
+            Request::List => writeln!(output, "list")?,
+            Request::Stop => writeln!(output, "stop")?,
+            Request::Unwrap { identity, stanzas } => {
+                writeln!(output, "unwrap")?;
+                writeln!(output, "{}", path_line(identity)?)?;
+                writeln!(output, "{}", stanzas.len())?;
+                for stanza in stanzas {
+                    write!(output, "{}", stanza.tag)?;
+                    for arg in &stanza.args {
+                        write!(output, " {}", arg)?;
+                    }
+                    writeln!(output)?;
+                    writeln!(output, "{}", BASE64_STANDARD_NO_PAD.encode(&stanza.body))?;
+                }
+            }
+        }
+        output.flush()?;
+        Ok(())
+    }
+
+    pub fn read<R: BufRead>(input: &mut R) -> Result<Self> {
+        match read_line(input)?.as_str() {
+            "list" => Ok(Request::List),
+            "stop" => Ok(Request::Stop),
+            "unwrap" => {
+                let identity = PathBuf::from(read_line(input)?);
+                let count: usize = read_line(input)?.parse()?;
+
+                let mut stanzas = Vec::new();
+                for _ in 0..count {
+                    let header = read_line(input)?;
+                    let mut words = header.split(' ').map(String::from);
+                    let tag = words.next().unwrap_or_default();
+                    let args = words.collect();
+                    let body = BASE64_STANDARD_NO_PAD.decode(read_line(input)?)?;
+                    stanzas.push(Stanza { tag, args, body });
+                }
+
+                Ok(Request::Unwrap { identity, stanzas })
+            }
+            other => Err(anyhow!("unknown request {:?}", other)),
+        }
+    }
+}
+
+impl Response {
+    pub fn write<W: Write>(&self, output: &mut W) -> Result<()> {
+        match self {
+            Response::Identities(held) => {
+                writeln!(output, "identities {}", held.len())?;
+                for held in held {
+                    write!(output, "{}", path_line(&held.path)?)?;
+                    for recipient in &held.recipients {
+                        write!(output, "\t{}", recipient)?;
+                    }
+                    writeln!(output)?;
+                }
+            }
+            Response::FileKey(file_key) => {
+                let encoded = BASE64_STANDARD_NO_PAD.encode(file_key.expose_secret());
+                writeln!(output, "file-key {}", encoded)?;
+            }
+            Response::NoMatch => writeln!(output, "no-match")?,
+            Response::Ok => writeln!(output, "ok")?,
+            Response::Error(message) => writeln!(output, "error {}", message.replace('\n', " "))?,
+        }
+        output.flush()?;
+        Ok(())
+    }
+
+    pub fn read<R: BufRead>(input: &mut R) -> Result<Self> {
+        let line = read_line(input)?;
+        let (word, rest) = line.split_once(' ').unwrap_or((&line, ""));
+
+        match word {
+            "identities" => {
+                let count: usize = rest.parse()?;
+                let mut held = Vec::new();
+                for _ in 0..count {
+                    let line = read_line(input)?;
+                    let mut fields = line.split('\t');
+                    let path = PathBuf::from(fields.next().unwrap_or_default());
+                    let recipients = fields.map(String::from).collect();
+                    held.push(Held { path, recipients });
+                }
+                Ok(Response::Identities(held))
+            }
+            "file-key" => {
+                let file_key = FileKey::try_init_with_mut(|file_key| {
+                    match BASE64_STANDARD_NO_PAD.decode_slice(rest, file_key) {
+                        Ok(len) if len == file_key.len() => Ok(()),
+                        _ => Err(anyhow!("invalid file key")),
+                    }
+                })?;
+                Ok(Response::FileKey(file_key))
+            }
+            "no-match" => Ok(Response::NoMatch),
+            "ok" => Ok(Response::Ok),
+            "error" => Ok(Response::Error(rest.to_string())),
+            other => Err(anyhow!("unknown response {:?}", other)),
+        }
+    }
+}
+
+fn read_line<R: BufRead>(input: &mut R) -> Result<String> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(anyhow!("connection closed"));
+    }
+    Ok(line.trim_end_matches('\n').to_string())
+}
+
+fn path_line(path: &std::path::Path) -> Result<&str> {
+    path.to_str()
+        .filter(|path| !path.contains(['\n', '\t']))
+        .ok_or(anyhow!("unsupported identity path {:?}", path))
+}
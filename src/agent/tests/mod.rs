@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use age::{secrecy::ExposeSecret, x25519, Recipient};
+use age_core::format::FileKey;
+use std::{
+    fs::{self, Permissions},
+    io::Cursor,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    bind,
+    protocol::{Held, Request, Response},
+    unwrap_stanzas,
+};
+use crate::file_io::Unlocked;
+
+fn round_trip_request(request: Request) -> Request {
+    let mut buffer = Vec::new();
+    request.write(&mut buffer).expect("write");
+    Request::read(&mut Cursor::new(buffer)).expect("read")
+}
+
+fn round_trip_response(response: Response) -> Response {
+    let mut buffer = Vec::new();
+    response.write(&mut buffer).expect("write");
+    Response::read(&mut Cursor::new(buffer)).expect("read")
+}
+
+#[test]
+fn protocol_round_trip() {
+    let identity = x25519::Identity::generate();
+    let file_key = FileKey::new(Box::new([7; 16]));
+    let (stanzas, _) = identity.to_public().wrap_file_key(&file_key).expect("wraps");
+
+    let Request::Unwrap { identity: path, stanzas: read } = round_trip_request(Request::Unwrap {
+        identity: PathBuf::from("/home/jane/.config/boxednix/jane"),
+        stanzas,
+    }) else {
+        panic!("expected unwrap request");
+    };
+    assert_eq!(path, PathBuf::from("/home/jane/.config/boxednix/jane"));
+    assert_eq!(read.len(), 1);
+    assert_eq!(read[0].tag, "X25519");
+
+    let Response::FileKey(read) = round_trip_response(Response::FileKey(file_key)) else {
+        panic!("expected file key");
+    };
+    assert_eq!(read.expose_secret(), &[7; 16]);
+
+    let held = vec![Held {
+        path: PathBuf::from("/home/jane/.ssh/id_ed25519"),
+        recipients: vec!["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHsKLqeplhpW+uObz5dvMgjz1OxfM/XXUB+VHtZ6isGN".into()],
+    }];
+    let Response::Identities(read) = round_trip_response(Response::Identities(held.clone())) else {
+        panic!("expected identities");
+    };
+    assert_eq!(read, held);
+}
+
+#[test]
+fn unlock_and_unwrap() {
+    let identity = x25519::Identity::generate();
+    let dir = tempfile::tempdir().expect("temp dir");
+    let path = dir.path().join("identity");
+    let content = format!("# public key: {}\n{}\n", identity.to_public(), identity.to_string().expose_secret());
+    std::fs::write(&path, content).expect("write");
+
+    let unlocked = Unlocked::read(&path, "test").expect("unlocks");
+    assert_eq!(unlocked.recipients(), [identity.to_public().to_string()]);
+    assert!(!unlocked.protected);
+
+    let file_key = FileKey::new(Box::new([42; 16]));
+    let (stanzas, _) = identity.to_public().wrap_file_key(&file_key).expect("wraps");
+    let unwrapped = unwrap_stanzas(&unlocked, &stanzas).expect("matches").expect("unwraps");
+    assert_eq!(unwrapped.expose_secret(), &[42; 16]);
+
+    let other = x25519::Identity::generate();
+    let (stanzas, _) = other.to_public().wrap_file_key(&file_key).expect("wraps");
+    assert!(unwrap_stanzas(&unlocked, &stanzas).is_none());
+}
+
+#[test]
+fn binds_only_in_private_dirs() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let mode = |path: &Path| {
+        fs::metadata(path).expect("metadata").permissions().mode() & 0o777
+    };
+
+    // Like `$HOME`, which must not be changed.
+    let shared = dir.path().join("home");
+    fs::create_dir(&shared).expect("creates");
+    fs::set_permissions(&shared, Permissions::from_mode(0o755)).expect("chmods");
+    assert!(bind(&shared.join("agent.sock")).is_err());
+    assert_eq!(mode(&shared), 0o755);
+
+    let private = shared.join("boxednix");
+    let _listener = bind(&private.join("agent.sock")).expect("binds");
+    assert_eq!(mode(&private), 0o700);
+    assert_eq!(mode(&private.join("agent.sock")), 0o600);
+}
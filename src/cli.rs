@@ -5,7 +5,9 @@ use clap::{CommandFactory, Parser, Subcommand};
 use clap_complete::Shell;
 use log::error;
 use simplelog::{CombinedLogger, Config, LevelFilter, WriteLogger};
-use std::{env, fs::File, io, path::PathBuf};
+use std::{fs::File, io, path::PathBuf};
+
+use boxednix::Protection;
 
 use crate::Result;
 
@@ -18,18 +20,29 @@ use crate::Result;
     subcommand_help_heading = "Subcommands"
 )]
 struct Cli {
+    /// Sources to edit together in one session.
     #[arg(required = true)]
-    pub source: Option<PathBuf>,
+    pub sources: Vec<PathBuf>,
 
     #[arg(short, long)]
     pub config: Option<PathBuf>,
 
+    /// Identity to decrypt with, tried in the given order. Replaces the configured ones.
+    #[arg(short, long = "identity", value_name = "IDENTITY")]
+    pub identities: Vec<PathBuf>,
+
+    /// Editor command, like `code --wait`. Defaults to `$VISUAL`, then `$EDITOR`, then `vi`.
     #[arg(short, long)]
     pub editor: Option<String>,
 
-    #[arg(trailing_var_arg = true, requires = "editor")]
+    /// Passed to the editor after the files, following `--`. Replaces the configured ones.
+    #[arg(last = true)]
     pub editor_args: Vec<String>,
 
+    /// Check the file when the editor exits and offer to re-open it on problems.
+    #[arg(long)]
+    pub validate: bool,
+
     #[arg(long, global = true)]
     pub debug: bool,
 
@@ -40,7 +53,7 @@ struct Cli {
 #[derive(Subcommand)]
 enum Sub {
     New {
-        identity: PathBuf,
+        identity: Option<PathBuf>,
 
         #[arg(short, long)]
         dir: Option<PathBuf>,
@@ -48,12 +61,69 @@ enum Sub {
         #[arg(short, long)]
         passphrase: bool,
 
+        /// Encrypt the sources to a passphrase instead of an identity.
+        #[arg(
+            long,
+            conflicts_with_all = ["identity", "passphrase", "recipients", "recipients_files"]
+        )]
+        passphrase_only: bool,
+
         #[arg(short, long)]
-        recipients: Vec<PathBuf>,
+        recipients: Vec<String>,
 
         #[arg(short = 'R', long)]
         recipients_files: Vec<PathBuf>,
+
+        #[arg(short = 'x', long)]
+        extends: Option<PathBuf>,
+
+        /// Use an SSH key as identity instead of generating an age key.
+        #[arg(
+            short,
+            long,
+            value_name = "KEY",
+            num_args = 0..=1,
+            default_missing_value = "~/.ssh/id_ed25519",
+            conflicts_with_all = ["identity", "passphrase", "passphrase_only"]
+        )]
+        ssh: Option<PathBuf>,
     },
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Manage identity files. Defaults to the project's first identity.
+    Identity {
+        #[command(subcommand)]
+        command: IdentityCommand,
+    },
+    /// Keep identities unlocked for other `bx` processes, like ssh-agent.
+    Agent {
+        /// Identities to unlock. Defaults to the project's identities.
+        identities: Vec<PathBuf>,
+
+        /// Minutes until the identities are dropped.
+        #[arg(short, long, default_value_t = 60)]
+        timeout: u64,
+
+        /// Stop the running agent.
+        #[arg(long, conflicts_with_all = ["identities", "timeout"])]
+        stop: bool,
+    },
+    /// List sessions that crashed before their last save was encrypted, and
+    /// restore their last save to the source.
+    Recover {
+        /// Session to restore, as listed.
+        session: Option<String>,
+
+        /// Delete the journal of the session instead of restoring it.
+        #[arg(long, requires = "session")]
+        discard: bool,
+    },
+    /// List the kept versions of a source with the attributes they changed.
+    History { source: PathBuf },
+    /// Replace a source with a version listed by `history`.
+    Restore { source: PathBuf, version: usize },
     Copy {
         source: PathBuf,
         target: Option<PathBuf>,
@@ -63,6 +133,69 @@ enum Sub {
     },
 }
 
+#[derive(Subcommand)]
+enum ConfigCommand {
+    Show,
+    Migrate,
+}
+
+#[derive(Subcommand)]
+enum IdentityCommand {
+    /// Print the public keys of an identity.
+    ShowPublic { identity: Option<PathBuf> },
+    /// Add, change or remove the passphrase of an identity.
+    Passphrase {
+        identity: Option<PathBuf>,
+
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Replace an identity with a new key and rekey the project's files.
+    Rotate { identity: Option<PathBuf> },
+    /// Print an age key as Shamir shares, some of which rebuild it.
+    Split {
+        identity: Option<PathBuf>,
+
+        /// Number of shares.
+        #[arg(short, long)]
+        shares: u8,
+
+        /// Number of shares needed to rebuild the key.
+        #[arg(short, long)]
+        threshold: u8,
+    },
+    /// Rebuild an identity from Shamir shares read from stdin.
+    Combine {
+        identity: PathBuf,
+
+        #[arg(short, long)]
+        passphrase: bool,
+    },
+    /// Print an identity as a paper backup, or without its passphrase.
+    Export {
+        identity: Option<PathBuf>,
+
+        /// Print the key as words and QR code, for a paper backup.
+        #[arg(long, required_unless_present = "unprotected")]
+        paper: bool,
+
+        /// Print the identity file without its passphrase, to a file or pipe only.
+        #[arg(long, conflicts_with = "paper")]
+        unprotected: bool,
+    },
+    /// Write an identity read from stdin.
+    Import {
+        identity: PathBuf,
+
+        /// Read a paper backup printed by `export --paper`.
+        #[arg(long)]
+        paper: bool,
+
+        #[arg(short, long)]
+        passphrase: bool,
+    },
+}
+
 pub fn run() -> Result<()> {
     let cli = Cli::parse();
     if cli.debug {
@@ -70,10 +203,10 @@ pub fn run() -> Result<()> {
     }
 
     match handle_cli(cli) {
-        Ok(_) => return Ok(()),
+        Ok(_) => Ok(()),
         Err(error) => {
             error!("{:?}", error);
-            return Err(error);
+            Err(error)
         }
     }
 }
@@ -97,15 +230,63 @@ fn handle_cli(cli: Cli) -> Result<()> {
                 recipients,
                 recipients_files,
                 passphrase,
+                passphrase_only,
+                extends,
+                ssh,
             } => {
+                let protection = match (passphrase, passphrase_only) {
+                    (_, true) => Protection::PassphraseOnly,
+                    (true, false) => Protection::ProtectedIdentity,
+                    (false, false) => Protection::Identity,
+                };
                 return boxednix::create_config(
                     identity,
                     dir,
-                    passphrase,
+                    protection,
                     recipients,
                     recipients_files,
-                )
+                    extends,
+                    ssh,
+                );
             }
+            Sub::Agent {
+                identities,
+                timeout,
+                stop,
+            } => return boxednix::agent(identities, timeout, stop),
+            Sub::Recover { session, discard } => return boxednix::recover(session, discard),
+            Sub::History { source } => return boxednix::history(source),
+            Sub::Restore { source, version } => return boxednix::restore(source, version),
+            Sub::Config { command } => match command {
+                ConfigCommand::Show => return boxednix::show_config(),
+                ConfigCommand::Migrate => return boxednix::migrate_config(),
+            },
+            Sub::Identity { command } => match command {
+                IdentityCommand::ShowPublic { identity } => return boxednix::show_public(identity),
+                IdentityCommand::Passphrase { identity, remove } => {
+                    return boxednix::change_passphrase(identity, remove)
+                }
+                IdentityCommand::Rotate { identity } => return boxednix::rotate_identity(identity),
+                IdentityCommand::Split {
+                    identity,
+                    shares,
+                    threshold,
+                } => return boxednix::split_identity(identity, shares, threshold),
+                IdentityCommand::Combine {
+                    identity,
+                    passphrase,
+                } => return boxednix::combine_identity(identity, passphrase),
+                IdentityCommand::Export {
+                    identity,
+                    paper,
+                    unprotected,
+                } => return boxednix::export_identity(identity, paper, unprotected),
+                IdentityCommand::Import {
+                    identity,
+                    paper,
+                    passphrase,
+                } => return boxednix::import_identity(identity, paper, passphrase),
+            },
             Sub::Copy {
                 source: _,
                 target: _,
@@ -113,13 +294,13 @@ fn handle_cli(cli: Cli) -> Result<()> {
         }
     }
 
-    let source = cli.source.ok_or(anyhow!("Source are invalid"))?;
-    let editor = match cli.editor {
-        Some(editor) => editor,
-        None => env::var("EDITOR")?,
-    };
-
-    boxednix::run(source, &editor, &cli.editor_args)?;
+    boxednix::run(
+        cli.sources,
+        cli.identities,
+        cli.editor,
+        cli.editor_args,
+        cli.validate,
+    )?;
 
     Ok(())
 }
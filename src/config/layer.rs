@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::anyhow;
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::{self, Display, Write},
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+use super::{migrate, model::TomlConfig, resolve_path};
+use crate::{warn, Result};
+
+const ENV_VARS: [(&str, &str); 3] = [
+    ("BOXEDNIX_IDENTITY", "identity"),
+    ("BOXEDNIX_GENERATED_DIR", "generated_dir"),
+    ("BOXEDNIX_EDITOR", "editor"),
+];
+
+/// Keys holding paths, which are resolved relative to the file that sets them,
+/// or to the user dir if they are a bare name. Recipient files are resolved
+/// relative to the file too.
+const PATH_KEYS: [&str; 1] = ["identity"];
+
+#[derive(Clone, Debug)]
+pub enum Origin {
+    Default,
+    User(PathBuf),
+    Project(PathBuf),
+    Env(&'static str),
+}
+
+impl Origin {
+    /// The directory of the project config this value comes from.
+    pub fn project_dir(&self) -> Option<&Path> {
+        match self {
+            Origin::Project(path) => path.parent(),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "default"),
+            Origin::User(path) => write!(f, "user config {}", path.display()),
+            Origin::Project(path) => write!(f, "{}", path.display()),
+            Origin::Env(var) => write!(f, "${}", var),
+        }
+    }
+}
+
+pub struct Layer {
+    pub origin: Origin,
+    pub table: Table,
+}
+
+impl Layer {
+    pub fn new(origin: Origin, table: Table) -> Self {
+        Self { origin, table }
+    }
+
+    pub fn read(origin: Origin, path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let toml = std::fs::read_to_string(path)?;
+        let mut table: Table = toml::from_str(&toml).map_err(|e| anyhow!("{:?}: {}", path, e))?;
+
+        let version = migrate::migrate(&mut table).map_err(|e| anyhow!("{:?}: {}", path, e))?;
+        if version < migrate::VERSION {
+            let message = format!(
+                "{:?} uses config version {}, run `bx config migrate` to upgrade it",
+                path, version
+            );
+            warn!("{}", message);
+            eprintln!("warning: {}", message);
+        }
+
+        if let Some(dir) = path.parent() {
+            anchor_paths(&mut table, dir)?;
+        }
+
+        // Layers are merged untyped, so unknown or mistyped keys are caught here,
+        // where the file they come from is still known.
+        Value::Table(table.clone())
+            .try_into::<TomlConfig>()
+            .map_err(|e| anyhow!("{:?}: {}", path, e))?;
+
+        Ok(Some(Self { origin, table }))
+    }
+
+    pub fn from_env() -> Vec<Self> {
+        Self::from_vars(|var| env::var(var).ok())
+    }
+
+    /// The layers of the variables `lookup` finds, empty ones count as unset.
+    pub fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Vec<Self> {
+        ENV_VARS
+            .iter()
+            .filter_map(|(var, key)| {
+                let value = lookup(var).filter(|value| !value.is_empty())?;
+                let table = Table::from_iter([(key.to_string(), Value::String(value))]);
+                Some(Self::new(Origin::Env(var), table))
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct Merged {
+    pub table: Table,
+    pub origins: BTreeMap<String, Origin>,
+}
+
+impl Merged {
+    /// Merges the layers in order, later ones taking precedence. Tables are merged
+    /// key by key, every other value (including arrays) is replaced as a whole.
+    pub fn from_layers(layers: Vec<Layer>) -> Self {
+        let mut merged = Self::default();
+        for layer in layers {
+            merge(
+                &mut merged.table,
+                layer.table,
+                "",
+                &layer.origin,
+                &mut merged.origins,
+            );
+        }
+        merged
+    }
+
+    /// Renders the merged table as TOML, annotating every value with its origin.
+    pub fn render(&self) -> Result<String> {
+        let mut out = String::new();
+        self.render_table(&mut out, &self.table, "")?;
+        Ok(out)
+    }
+
+    fn render_table(&self, out: &mut String, table: &Table, prefix: &str) -> Result<()> {
+        for (key, value) in table.iter().filter(|(_, value)| !value.is_table()) {
+            let path = join_key(prefix, key);
+            match self.origins.get(&path) {
+                Some(origin) => writeln!(out, "{} = {}  # {}", key, value, origin)?,
+                None => writeln!(out, "{} = {}", key, value)?,
+            }
+        }
+
+        for (key, value) in table.iter() {
+            if let Value::Table(table) = value {
+                let path = join_key(prefix, key);
+                writeln!(out, "\n[{}]", path)?;
+                self.render_table(out, table, &path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn merge(
+    base: &mut Table,
+    over: Table,
+    prefix: &str,
+    origin: &Origin,
+    origins: &mut BTreeMap<String, Origin>,
+) {
+    for (key, value) in over {
+        let path = join_key(prefix, &key);
+        match value {
+            Value::Table(over) => {
+                let entry = base
+                    .entry(key)
+                    .or_insert_with(|| Value::Table(Table::new()));
+                if !entry.is_table() {
+                    *entry = Value::Table(Table::new());
+                }
+                if let Value::Table(base) = entry {
+                    merge(base, over, &path, origin, origins);
+                }
+            }
+            value => {
+                origins.retain(|key, _| !key.starts_with(&format!("{}.", path)));
+                origins.insert(path, origin.clone());
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn anchor_paths(table: &mut Table, dir: &Path) -> Result<()> {
+    let anchor = |value: &mut Value| {
+        if let Value::String(path) = value
+            && !path.starts_with('~')
+            && Path::new(path).is_relative()
+        {
+            *path = dir.join(&path).to_string_lossy().into_owned();
+        }
+    };
+    // A bare name is a file in the user dir.
+    let anchor_path = |value: &mut Value| -> Result<()> {
+        match value {
+            Value::String(path)
+                if !path.starts_with('~') && Path::new(path).components().count() == 1 =>
+            {
+                *path = resolve_path(PathBuf::from(&path))?.to_string_lossy().into_owned();
+            }
+            value => anchor(value),
+        }
+        Ok(())
+    };
+
+    for key in PATH_KEYS {
+        match table.get_mut(key) {
+            Some(Value::Array(values)) => values.iter_mut().try_for_each(anchor_path)?,
+            Some(value) => anchor_path(value)?,
+            None => {}
+        }
+    }
+
+    if let Some(Value::Array(recipients)) = table.get_mut("recipients") {
+        recipients
+            .iter_mut()
+            .filter_map(|recipient| recipient.get_mut("file"))
+            .for_each(anchor);
+    }
+    Ok(())
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::anyhow;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+use crate::Result;
+
+/// The schema version written by this build. Configs without a `version` are 0.
+pub const VERSION: i64 = 1;
+
+/// The version a config file was migrated from and the backup of the original.
+pub type Migrated = (i64, PathBuf);
+
+/// Upgrades a config table to the current schema and returns the version it had.
+pub fn migrate(table: &mut Table) -> Result<i64> {
+    let version = match table.get("version") {
+        None => 0,
+        Some(Value::Integer(version)) => *version,
+        Some(value) => return Err(anyhow!("invalid `version = {}`", value)),
+    };
+
+    if version > VERSION {
+        return Err(anyhow!(
+            "config version {} is newer than the supported version {}, please update boxednix",
+            version,
+            VERSION
+        ));
+    }
+
+    if version < 1 {
+        v0_to_v1(table)?;
+    }
+
+    if version < VERSION {
+        let rest = std::mem::take(table);
+        table.insert("version".into(), Value::Integer(VERSION));
+        table.extend(rest.into_iter().filter(|(key, _)| key != "version"));
+    }
+
+    Ok(version)
+}
+
+/// Migrates a config file in place, keeping the original next to it as
+/// `<name>.v<version>.bak`. Returns the old version and the backup, if the
+/// file had to be migrated.
+pub fn migrate_file(path: &Path) -> Result<Option<Migrated>> {
+    let toml = std::fs::read_to_string(path)?;
+    let mut table: Table = toml::from_str(&toml).map_err(|e| anyhow!("{:?}: {}", path, e))?;
+
+    let version = migrate(&mut table).map_err(|e| anyhow!("{:?}: {}", path, e))?;
+    if version == VERSION {
+        return Ok(None);
+    }
+
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", version));
+    let backup = PathBuf::from(backup);
+
+    std::fs::copy(path, &backup)?;
+    std::fs::write(path, toml::to_string_pretty(&table)?)?;
+    Ok(Some((version, backup)))
+}
+
+/// - `update_flake_input` becomes a `post_generate` hook.
+/// - `recipients` and `recipients_files` become typed `recipients` entries.
+///   Entries that are typed already are kept.
+fn v0_to_v1(table: &mut Table) -> Result<()> {
+    if let Some(Value::String(input)) = table.get("update_flake_input") {
+        let command = format!("nix flake update '{}'", input.replace('\'', "'\\''"));
+        table.remove("update_flake_input");
+
+        let hooks = table
+            .entry("hooks")
+            .or_insert_with(|| Value::Table(Table::new()));
+        if let Value::Table(hooks) = hooks {
+            let post_generate = hooks
+                .entry("post_generate")
+                .or_insert_with(|| Value::Array(Vec::new()));
+            if let Value::Array(commands) = post_generate {
+                commands.insert(0, Value::String(command));
+            }
+        }
+    }
+
+    let mut recipients = Vec::new();
+
+    if let Some(Value::Array(keys)) = table.get("recipients") {
+        for key in keys {
+            match key {
+                // Plain recipients used to be read as paths, so anything that
+                // doesn't look like a key is most likely a recipients file.
+                Value::String(key) if key.starts_with("age1") || key.starts_with("ssh-") => {
+                    recipients.push(entry("key", key));
+                }
+                Value::String(file) => recipients.push(entry("file", file)),
+                Value::Table(_) => recipients.push(key.clone()),
+                _ => return Err(anyhow!("invalid recipient `{}`", key)),
+            }
+        }
+    }
+
+    if let Some(Value::Array(files)) = table.remove("recipients_files") {
+        for file in files {
+            match file.as_str() {
+                Some(file) => recipients.push(entry("file", file)),
+                None => return Err(anyhow!("invalid recipients file `{}`", file)),
+            }
+        }
+    }
+
+    if !recipients.is_empty() {
+        table.insert("recipients".into(), Value::Array(recipients));
+    }
+    Ok(())
+}
+
+fn entry(kind: &str, value: &str) -> Value {
+    Value::Table(Table::from_iter([(kind.to_string(), Value::from(value))]))
+}
@@ -1,89 +1,345 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
+mod layer;
+mod migrate;
 mod model;
 
+#[cfg(test)]
+mod tests;
+
 use anyhow::anyhow;
 use directories_next::ProjectDirs;
+use layer::{Layer, Merged, Origin};
+use migrate::Migrated;
 use model::TomlConfig;
+use resolve_path::PathResolveExt;
 use std::{
     ffi::OsString,
     path::{Path, PathBuf},
 };
+use toml::{Table, Value};
 
-use crate::Result;
+use crate::{gitignore, redact::Params, Result};
 
-pub use model::Config;
+pub use model::{Config, Gitignore, Hooks, OnFailure, Recipient};
 
 const CONFIG_FILE: &str = "boxednix.toml";
+const USER_CONFIG_FILE: &str = "config.toml";
+/// Earlier versions kept of each source, unless `history` is set.
+const DEFAULT_HISTORY: usize = 10;
 
 pub fn create_default(
     cwd: &Path,
-    identity: PathBuf,
+    identity: Option<PathBuf>,
     dir: Option<PathBuf>,
-    recipients: Vec<PathBuf>,
+    recipients: Vec<String>,
     recipients_files: Vec<PathBuf>,
+    extends: Option<PathBuf>,
+    passphrase_only: bool,
 ) -> Result<TomlConfig> {
     let output = cwd.join(CONFIG_FILE);
     if output.exists() {
         return Err(anyhow!("A configuration already exists"));
     }
 
-    let identity = resolve_path(identity)?;
-    let recipients = recipients
-        .into_iter()
-        .map(resolve_path)
-        .collect::<Result<_, _>>()?;
+    let mut project_layers = Vec::new();
+    if let Some(extends) = &extends {
+        project_layers = read_project_layers(&cwd.join(extends))?;
+    }
+    let workspace_root = workspace_root(&project_layers).unwrap_or(cwd).to_path_buf();
 
-    let recipients_files = recipients_files
-        .into_iter()
-        .map(resolve_path)
-        .collect::<Result<_, _>>()?;
+    let mut layers = vec![user_layer(&workspace_root)?];
+    layers.extend(project_layers);
+    let inherited: TomlConfig = Value::Table(Merged::from_layers(layers).table).try_into()?;
+
+    let identity = identity.map(resolve_path).transpose()?;
+    if identity.is_none() && inherited.identities.is_empty() && !passphrase_only {
+        return Err(anyhow!(
+            "No identity given and none set in {:?}",
+            user_config_file()?
+        ));
+    }
 
-    let generated_dir = match dir {
-        Some(dir) => dir,
-        None => generated_dir(cwd)?,
+    let mut recipients: Vec<_> = recipients.into_iter().map(Recipient::Key).collect();
+    for file in recipients_files {
+        recipients.push(Recipient::File(resolve_path(file)?));
+    }
+
+    // A project extending another one shares its generated dir unless told
+    // otherwise, and one set in the user config keeps coming from there.
+    let project_generated_dir = match (dir, &extends) {
+        (Some(dir), _) => Some(dir),
+        (None, Some(_)) => None,
+        (None, None) => match inherited.generated_dir {
+            Some(_) => None,
+            None => Some(generated_dir(cwd)?),
+        },
     };
 
-    let config = TomlConfig {
-        identity,
+    let mut config = TomlConfig {
+        version: Some(migrate::VERSION),
+        extends,
+        identities: identity.into_iter().collect(),
         recipients,
-        recipients_files,
-        generated_dir,
-        update_flake_input: None,
+        passphrase_only: passphrase_only.then_some(true),
+        generated_dir: project_generated_dir,
+        ..Default::default()
     };
-
     let toml = toml::to_string_pretty(&config)?;
+
+    // Inherited values are not written to the project, but callers still need to
+    // know which ones are in effect.
+    config.identities = match config.identities.pop() {
+        Some(identity) => vec![identity.try_resolve()?.into_owned()],
+        None => inherited.identities,
+    };
+    config.generated_dir = match config.generated_dir.or(inherited.generated_dir) {
+        Some(dir) => Some(dir),
+        None => Some(generated_dir(&workspace_root)?),
+    };
+    config.gitignore = inherited.gitignore;
+
+    // Relative generated dirs depend on the source and are checked per session.
+    if let Some(dir) = config.generated_dir.as_ref().filter(|dir| dir.is_absolute()) {
+        gitignore::check(
+            &dir.join("flake.nix"),
+            cwd,
+            config.gitignore.unwrap_or_default(),
+        )?;
+    }
+
     std::fs::write(output, toml)?;
     Ok(config)
 }
 
+/// Loads the effective configuration. Values are taken from the user config,
+/// then from the configs the project's `boxednix.toml` extends, outermost
+/// first, then from the project's own, then from `BOXEDNIX_*` environment
+/// variables, each one overriding the previous.
 pub fn load(cwd: &Path, source: &Path) -> Result<Config> {
     let source_dir = source
         .parent()
         .ok_or(anyhow!("can't get parent of source ({:?}).)", source))?;
 
-    let project_root = find_project_root(&cwd).ok_or(anyhow!(
+    let project_root = find_project_root(cwd).ok_or(anyhow!(
         "Config file not found. Current working dir: {:?}",
         cwd
     ))?;
 
-    let config_path = project_root.join(CONFIG_FILE);
-    let toml = std::fs::read_to_string(config_path)?;
-    let toml_config: TomlConfig = toml::from_str(&toml)?;
+    let (merged, workspace_root) = merge_layers(project_root)?;
+
+    // Files are generated relative to the config that set `generated_dir`, so
+    // projects extending a shared config don't overwrite each other's files.
+    let generated_root = merged
+        .origins
+        .get("generated_dir")
+        .and_then(Origin::project_dir)
+        .unwrap_or(&workspace_root)
+        .to_path_buf();
+
+    let toml_config: TomlConfig = Value::Table(merged.table).try_into()?;
+
+    let passphrase_only = toml_config.passphrase_only.unwrap_or_default();
+    if passphrase_only && !toml_config.recipients.is_empty() {
+        return Err(anyhow!(
+            "`passphrase_only` files can't have `recipients`, remove them from the configs of {:?}",
+            project_root
+        ));
+    }
+    if toml_config.identities.is_empty() && !passphrase_only {
+        return Err(anyhow!(
+            "No identity configured. Set `identity` in {:?} or {:?}",
+            project_root.join(CONFIG_FILE),
+            user_config_file()?
+        ));
+    }
+
+    let generated_dir = toml_config
+        .generated_dir
+        .ok_or(anyhow!("No generated dir configured"))?;
 
     Ok(Config {
-        identity: toml_config.identity,
+        identities: toml_config.identities,
         recipients: toml_config.recipients,
-        recipients_files: toml_config.recipients_files,
-        file_name: file_name(&source)?,
-        target_dir: target_dir(&cwd, &project_root, &source_dir, &toml_config.generated_dir)?,
-        flake_input: toml_config.update_flake_input,
+        passphrase_only,
+        file_name: file_name(source)?,
+        target_dir: target_dir(cwd, &generated_root, source_dir, &generated_dir)?,
+        project_root: project_root.to_path_buf(),
+        editor: toml_config.editor,
+        editor_args: toml_config.editor_args,
+        validate: toml_config.validate.unwrap_or_default(),
+        history: toml_config.history.unwrap_or(DEFAULT_HISTORY),
+        keywords: toml_config.keywords.params(),
+        hooks: toml_config.hooks,
+        gitignore: toml_config.gitignore.unwrap_or_default(),
     })
 }
 
+/// Renders the effective configuration, with the origin of every value.
+pub fn show(cwd: &Path) -> Result<String> {
+    let project_root = find_project_root(cwd).ok_or(anyhow!(
+        "Config file not found. Current working dir: {:?}",
+        cwd
+    ))?;
+
+    let (merged, _) = merge_layers(project_root)?;
+    merged.render()
+}
+
+/// The identities configured for the project in `cwd`.
+pub fn identities(cwd: &Path) -> Result<Vec<PathBuf>> {
+    let project_root = find_project_root(cwd).ok_or(anyhow!(
+        "Config file not found. Current working dir: {:?}",
+        cwd
+    ))?;
+
+    let (merged, _) = merge_layers(project_root)?;
+    let toml_config: TomlConfig = Value::Table(merged.table).try_into()?;
+    if toml_config.identities.is_empty() {
+        return Err(anyhow!("No identity configured"));
+    }
+    Ok(toml_config.identities)
+}
+
+/// The files that name the project's recipients: the project config, every
+/// config it extends and the recipients files they list.
+pub fn recipient_sources(cwd: &Path) -> Result<Vec<PathBuf>> {
+    let project_root = find_project_root(cwd).ok_or(anyhow!(
+        "Config file not found. Current working dir: {:?}",
+        cwd
+    ))?;
+
+    let mut paths = Vec::new();
+    for layer in read_project_layers(&project_root.join(CONFIG_FILE))? {
+        if let Some(Value::Array(recipients)) = layer.table.get("recipients") {
+            let files = recipients
+                .iter()
+                .filter_map(|recipient| recipient.get("file").and_then(Value::as_str));
+            paths.extend(files.map(PathBuf::from));
+        }
+        if let Origin::Project(path) = layer.origin {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Migrates the user config and the project config, with every config it
+/// extends, to the current schema. Returns each file with its old version and
+/// backup, if it was migrated.
+pub fn migrate(cwd: &Path) -> Result<Vec<(PathBuf, Option<Migrated>)>> {
+    let mut paths = Vec::new();
+
+    let user_config = user_config_file()?;
+    if user_config.exists() {
+        paths.push(user_config);
+    }
+
+    if let Some(project_root) = find_project_root(cwd) {
+        for layer in read_project_layers(&project_root.join(CONFIG_FILE))? {
+            if let Origin::Project(path) = layer.origin {
+                paths.push(path);
+            }
+        }
+    }
+
+    if paths.is_empty() {
+        return Err(anyhow!("No config found. Current working dir: {:?}", cwd));
+    }
+
+    paths
+        .into_iter()
+        .map(|path| migrate::migrate_file(&path).map(|result| (path, result)))
+        .collect()
+}
+
+/// Merges all configuration layers, from lowest to highest precedence:
+/// built-in defaults, the user config, the configs named by `extends` (the
+/// outermost first), the project's `boxednix.toml` and `BOXEDNIX_*` environment
+/// variables.
+///
+/// Returns the merged layers and the workspace root, the directory of the
+/// outermost project config.
+fn merge_layers(project_root: &Path) -> Result<(Merged, PathBuf)> {
+    let project_layers = read_project_layers(&project_root.join(CONFIG_FILE))?;
+    let workspace_root = workspace_root(&project_layers)
+        .unwrap_or(project_root)
+        .to_path_buf();
+
+    let mut layers = vec![default_layer(&workspace_root)?, user_layer(&workspace_root)?];
+    layers.extend(project_layers);
+    layers.extend(Layer::from_env());
+
+    Ok((Merged::from_layers(layers), workspace_root))
+}
+
+/// Reads a project config and, following `extends`, every config it builds on.
+/// The outermost config comes first.
+fn read_project_layers(config_path: &Path) -> Result<Vec<Layer>> {
+    let mut layers: Vec<Layer> = Vec::new();
+    let mut next = Some(config_path.to_path_buf());
+
+    while let Some(path) = next {
+        let path = path
+            .canonicalize()
+            .map_err(|e| anyhow!("Can't read config {:?}: {}", path, e))?;
+        if layers
+            .iter()
+            .any(|layer| matches!(&layer.origin, Origin::Project(p) if p == &path))
+        {
+            return Err(anyhow!("Config {:?} extends itself", path));
+        }
+
+        let mut layer = Layer::read(Origin::Project(path.clone()), &path)?
+            .ok_or(anyhow!("Config not found: {:?}", path))?;
+
+        next = match layer.table.remove("extends") {
+            Some(Value::String(extends)) => {
+                let dir = path.parent().unwrap_or(Path::new("/"));
+                Some(extends.try_resolve_in(dir)?.into_owned())
+            }
+            Some(value) => return Err(anyhow!("{:?}: invalid `extends = {}`", path, value)),
+            None => None,
+        };
+
+        layers.push(layer);
+    }
+
+    layers.reverse();
+    Ok(layers)
+}
+
+fn workspace_root(project_layers: &[Layer]) -> Option<&Path> {
+    project_layers
+        .first()
+        .and_then(|layer| layer.origin.project_dir())
+}
+
+fn default_layer(workspace_root: &Path) -> Result<Layer> {
+    let params = Params::default();
+    let generated_dir = generated_dir(workspace_root)?;
+
+    let mut keywords = Table::new();
+    keywords.insert("bcrypt_cost".into(), Value::from(params.bcrypt_cost));
+    keywords.insert("sha512_rounds".into(), Value::from(params.sha512_rounds as i64));
+
+    let mut table = Table::new();
+    table.insert(
+        "generated_dir".into(),
+        Value::String(generated_dir.to_string_lossy().into_owned()),
+    );
+    table.insert("keywords".into(), Value::Table(keywords));
+    table.insert("gitignore".into(), Value::from("none"));
+
+    Ok(Layer::new(Origin::Default, table))
+}
+
 fn target_dir(cwd: &Path, root: &Path, source_dir: &Path, target_dir: &Path) -> Result<PathBuf> {
     if target_dir.is_absolute() {
-        cwd.join(source_dir)
+        let source_dir = cwd.join(source_dir);
+        source_dir
+            .canonicalize()
+            .unwrap_or(source_dir)
             .strip_prefix(root)
             .map(|sub_dir| target_dir.join(sub_dir))
             .map_err(|e| {
@@ -109,7 +365,7 @@ fn file_name(source: &Path) -> Result<OsString> {
     Ok(stem)
 }
 
-pub fn find_project_root<'a>(cwd: &'a Path) -> Option<&'a Path> {
+pub fn find_project_root(cwd: &Path) -> Option<&Path> {
     let mut current = cwd;
 
     loop {
@@ -118,14 +374,32 @@ pub fn find_project_root<'a>(cwd: &'a Path) -> Option<&'a Path> {
             return Some(current);
         }
 
-        current = match current.parent() {
-            Some(parent) => parent,
-            None => return None,
-        };
+        current = current.parent()?;
     }
 }
 
-fn resolve_path(input: PathBuf) -> Result<PathBuf> {
+/// Reads the user config. Its `generated_dir` is the directory that holds the
+/// generated files of all projects, so it is narrowed down to the project here.
+fn user_layer(project_root: &Path) -> Result<Layer> {
+    let path = user_config_file()?;
+    let mut layer = Layer::read(Origin::User(path.clone()), &path)?
+        .unwrap_or(Layer::new(Origin::User(path), Table::new()));
+
+    if let Some(Value::String(dir)) = layer.table.get("generated_dir") {
+        let name = project_root
+            .file_name()
+            .ok_or(anyhow!("project root has no name: {:?}", project_root))?;
+        let dir = Path::new(dir).join(name).to_string_lossy().into_owned();
+        layer
+            .table
+            .insert("generated_dir".into(), Value::String(dir));
+    }
+
+    Ok(layer)
+}
+
+/// Resolves a bare name to a file in the user dir.
+pub fn resolve_path(input: PathBuf) -> Result<PathBuf> {
     if input.components().count() == 1 && !input.is_absolute() {
         user_dir().map(|root| root.join(&input))
     } else {
@@ -133,12 +407,27 @@ fn resolve_path(input: PathBuf) -> Result<PathBuf> {
     }
 }
 
-fn user_dir<'a>() -> Result<PathBuf> {
+fn user_dir() -> Result<PathBuf> {
     ProjectDirs::from("tlm", "depeh", "boxednix")
         .map(|project| project.config_dir().to_path_buf())
         .ok_or(anyhow!("can't find user dir"))
 }
 
+/// Where `bx` keeps state between runs: `$XDG_STATE_HOME/boxednix`, or the
+/// local data dir.
+pub fn state_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir).join("boxednix"));
+    }
+    ProjectDirs::from("tlm", "depeh", "boxednix")
+        .map(|project| project.data_local_dir().to_path_buf())
+        .ok_or(anyhow!("can't find state dir"))
+}
+
+fn user_config_file() -> Result<PathBuf> {
+    user_dir().map(|dir| dir.join(USER_CONFIG_FILE))
+}
+
 fn generated_dir(cwd: &Path) -> Result<PathBuf> {
     let user_dir = user_dir()?;
     cwd.file_name()
@@ -1,66 +1,212 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use resolve_path::PathResolveExt;
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{ffi::OsString, path::PathBuf};
 
+use crate::redact::Params;
+
 pub struct Config {
-    pub identity: PathBuf,
-    pub recipients: Vec<PathBuf>,
-    pub recipients_files: Vec<PathBuf>,
+    pub identities: Vec<PathBuf>,
+    pub recipients: Vec<Recipient>,
+    pub passphrase_only: bool,
     pub file_name: OsString,
     pub target_dir: PathBuf,
-    pub flake_input: Option<String>,
+    pub project_root: PathBuf,
+    pub editor: Option<String>,
+    pub editor_args: Vec<String>,
+    pub validate: bool,
+    pub history: usize,
+    pub keywords: Params,
+    pub hooks: Hooks,
+    pub gitignore: Gitignore,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct TomlConfig {
-    #[serde(deserialize_with = "deserialize_pathbuf_resolve")]
-    pub identity: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<PathBuf>,
 
+    /// Tried in order when decrypting, so `identity` can be a single path or a list.
     #[serde(
-        default = "empty_pathbuf_vec",
-        deserialize_with = "deserialize_pathbuf_vec_resolve",
+        rename = "identity",
+        default,
+        deserialize_with = "deserialize_one_or_many_pathbuf_resolve",
+        serialize_with = "serialize_one_or_many",
         skip_serializing_if = "Vec::is_empty"
     )]
-    pub recipients: Vec<PathBuf>,
+    pub identities: Vec<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<Recipient>,
+
+    /// Encrypt to a passphrase, asked for on every run, instead of identities and recipients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase_only: Option<bool>,
+
+    #[serde(
+        default,
+        deserialize_with = "deserialize_option_pathbuf_expand_home",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub generated_dir: Option<PathBuf>,
+
+    /// A command like `code --wait`, split into words like a shell does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub editor: Option<String>,
+
+    /// Passed to the editor after the file.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub editor_args: Vec<String>,
+
+    /// Check the file when the editor exits and offer to re-open it on problems.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validate: Option<bool>,
+
+    /// Earlier encrypted versions kept of each source, for `bx history`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Keywords::is_empty")]
+    pub keywords: Keywords,
+
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gitignore: Option<Gitignore>,
+}
+
+/// A recipient to encrypt to, written as `{ key = "age1..." }` or
+/// `{ file = "./recipients.txt" }`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Recipient {
+    Key(String),
+    File(#[serde(deserialize_with = "deserialize_pathbuf_resolve")] PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_encrypt: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_encrypt: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_generate: Vec<String>,
 
-    #[serde(default = "empty_pathbuf_vec", skip_serializing_if = "Vec::is_empty")]
-    pub recipients_files: Vec<PathBuf>,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre_encrypt.is_empty() && self.post_encrypt.is_empty() && self.post_generate.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    #[default]
+    Warn,
+    Fatal,
+}
 
-    pub generated_dir: PathBuf,
-    pub update_flake_input: Option<String>,
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Keywords {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bcrypt_cost: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha512_rounds: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Generate {
-    pub dir: PathBuf,
+impl Keywords {
+    pub fn is_empty(&self) -> bool {
+        self.bcrypt_cost.is_none() && self.sha512_rounds.is_none()
+    }
+
+    pub fn params(&self) -> Params {
+        let default = Params::default();
+        Params {
+            bcrypt_cost: self.bcrypt_cost.unwrap_or(default.bcrypt_cost),
+            sha512_rounds: self.sha512_rounds.unwrap_or(default.sha512_rounds),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+/// What to do when generated files end up in a git work tree without being ignored.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
 pub enum Gitignore {
+    /// Add an entry to the work tree's `.gitignore`.
     Always,
+    /// Leave `.gitignore` alone and warn.
+    #[default]
     None,
+    /// Refuse to generate.
+    Strict,
+}
+
+fn deserialize_one_or_many_pathbuf_resolve<'de, D>(
+    deserializer: D,
+) -> Result<Vec<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => resolve_path(s).map(|path| vec![path]),
+        OneOrMany::Many(list) => list.into_iter().map(resolve_path).collect(),
+    }
 }
 
-fn empty_pathbuf_vec() -> Vec<PathBuf> {
-    Vec::new()
+fn serialize_one_or_many<S>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match paths {
+        [path] => path.serialize(serializer),
+        paths => paths.serialize(serializer),
+    }
 }
 
-fn deserialize_pathbuf_resolve<'de, D>(deserializer: D) -> Result<PathBuf, D::Error>
+// Relative generated dirs are relative to the source file, so only `~` is resolved.
+fn deserialize_option_pathbuf_expand_home<'de, D>(
+    deserializer: D,
+) -> Result<Option<PathBuf>, D::Error>
 where
     D: Deserializer<'de>,
 {
     let s = String::deserialize(deserializer)?;
-    resolve_path::<D::Error>(s)
+    if s.starts_with('~') {
+        resolve_path::<D::Error>(s).map(Some)
+    } else {
+        Ok(Some(PathBuf::from(s)))
+    }
 }
 
-fn deserialize_pathbuf_vec_resolve<'de, D>(deserializer: D) -> Result<Vec<PathBuf>, D::Error>
+fn deserialize_pathbuf_resolve<'de, D>(deserializer: D) -> Result<PathBuf, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let list = Vec::<String>::deserialize(deserializer)?;
-    list.into_iter().map(resolve_path).collect()
+    let s = String::deserialize(deserializer)?;
+    resolve_path::<D::Error>(s)
 }
 
 fn resolve_path<E>(s: String) -> Result<PathBuf, E>
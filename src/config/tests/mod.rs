@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use indoc::indoc;
+use std::{fs, path::PathBuf};
+use toml::{Table, Value};
+
+use super::{
+    layer::{Layer, Merged, Origin},
+    migrate::{self, VERSION},
+    model::TomlConfig,
+    resolve_path,
+};
+
+fn layer(origin: Origin, toml: &str) -> Layer {
+    Layer::new(origin, toml::from_str::<Table>(toml).expect("valid toml"))
+}
+
+#[test]
+fn merge_precedence() {
+    let user = PathBuf::from("/home/jane/.config/boxednix/config.toml");
+    let parent = PathBuf::from("/repo/boxednix.toml");
+    let project = PathBuf::from("/repo/sub/boxednix.toml");
+
+    let merged = Merged::from_layers(vec![
+        layer(
+            Origin::User(user.clone()),
+            indoc! {r#"
+                identity = "/home/jane/.config/boxednix/jane"
+                editor = "vim"
+
+                [keywords]
+                bcrypt_cost = 10
+                sha512_rounds = 6000
+            "#},
+        ),
+        layer(
+            Origin::Project(parent.clone()),
+            indoc! {r#"
+                recipients = [{ file = "/repo/admins.txt" }, { key = "age1jane" }]
+
+                [keywords]
+                bcrypt_cost = 12
+            "#},
+        ),
+        layer(
+            Origin::Project(project.clone()),
+            indoc! {r#"
+                recipients = [{ file = "/repo/sub/team.txt" }]
+            "#},
+        ),
+        layer(
+            Origin::Env("BOXEDNIX_EDITOR"),
+            indoc! {r#"
+                editor = "nano"
+            "#},
+        ),
+    ]);
+
+    let origin = |key: &str| merged.origins.get(key).map(|origin| origin.to_string());
+
+    assert_eq!(merged.table["editor"].as_str(), Some("nano"));
+    assert_eq!(origin("editor").as_deref(), Some("$BOXEDNIX_EDITOR"));
+
+    // Arrays are replaced, not appended.
+    let team: Table = toml::from_str(r#"recipients = [{ file = "/repo/sub/team.txt" }]"#)
+        .expect("valid toml");
+    assert_eq!(merged.table["recipients"], team["recipients"]);
+    assert_eq!(origin("recipients"), Some(project.display().to_string()));
+
+    // Tables are merged key by key.
+    let keywords = merged.table["keywords"].as_table().expect("table");
+    assert_eq!(keywords["bcrypt_cost"].as_integer(), Some(12));
+    assert_eq!(keywords["sha512_rounds"].as_integer(), Some(6000));
+    assert_eq!(origin("keywords.bcrypt_cost"), Some(parent.display().to_string()));
+    assert_eq!(
+        origin("keywords.sha512_rounds"),
+        Some(format!("user config {}", user.display()))
+    );
+}
+
+#[test]
+fn env_over_project_over_user() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let user = dir.path().join("config.toml");
+    let project = dir.path().join("boxednix.toml");
+    fs::write(
+        &user,
+        indoc! {r#"
+            version = 1
+            identity = ["keys/jane", "bob"]
+            editor = "vim"
+            generated_dir = "/generated/user"
+        "#},
+    )
+    .expect("writes");
+    fs::write(
+        &project,
+        indoc! {r#"
+            version = 1
+            editor = "nvim"
+            generated_dir = "/generated/project"
+        "#},
+    )
+    .expect("writes");
+
+    let mut layers = vec![
+        Layer::read(Origin::User(user.clone()), &user).expect("reads").expect("exists"),
+        Layer::read(Origin::Project(project.clone()), &project).expect("reads").expect("exists"),
+    ];
+    // An empty variable counts as unset.
+    layers.extend(Layer::from_vars(|var| match var {
+        "BOXEDNIX_EDITOR" => Some("nano".into()),
+        "BOXEDNIX_IDENTITY" => Some("".into()),
+        _ => None,
+    }));
+    let merged = Merged::from_layers(layers);
+    let origin = |key: &str| merged.origins.get(key).map(|origin| origin.to_string());
+
+    let config: TomlConfig = Value::Table(merged.table.clone()).try_into().expect("valid config");
+    assert_eq!(config.editor.as_deref(), Some("nano"));
+    assert_eq!(origin("editor").as_deref(), Some("$BOXEDNIX_EDITOR"));
+    assert_eq!(config.generated_dir, Some(PathBuf::from("/generated/project")));
+    assert_eq!(origin("generated_dir"), Some(project.display().to_string()));
+    // Relative paths are resolved next to the file that sets them, bare names
+    // in the user dir.
+    assert_eq!(
+        config.identities,
+        vec![
+            dir.path().join("keys/jane"),
+            resolve_path(PathBuf::from("bob")).expect("resolves")
+        ]
+    );
+    assert_eq!(origin("identity"), Some(format!("user config {}", user.display())));
+}
+
+#[test]
+fn migrate_v0() {
+    let mut table: Table = toml::from_str(indoc! {r#"
+        identity = "/home/jane/.config/boxednix/jane"
+        recipients = ["age1jane", { key = "age1bob" }, "./admins.txt"]
+        recipients_files = ["./hosts.txt"]
+        update_flake_input = "boxed"
+
+        [hooks]
+        post_generate = ["nixfmt \"$BOXEDNIX_TARGET\""]
+    "#})
+    .expect("valid toml");
+
+    assert_eq!(migrate::migrate(&mut table).expect("migrates"), 0);
+    assert_eq!(table.keys().next().map(String::as_str), Some("version"));
+    assert_eq!(table["version"].as_integer(), Some(VERSION));
+    assert!(!table.contains_key("update_flake_input"));
+    assert!(!table.contains_key("recipients_files"));
+
+    let expected: Table = toml::from_str(indoc! {r#"
+        recipients = [
+          { key = "age1jane" },
+          { key = "age1bob" },
+          { file = "./admins.txt" },
+          { file = "./hosts.txt" },
+        ]
+        post_generate = ["nix flake update 'boxed'", "nixfmt \"$BOXEDNIX_TARGET\""]
+    "#})
+    .expect("valid toml");
+    assert_eq!(table["recipients"], expected["recipients"]);
+    assert_eq!(table["hooks"]["post_generate"], expected["post_generate"]);
+
+    // Migrated tables are valid and migrating again changes nothing.
+    Value::Table(table.clone())
+        .try_into::<TomlConfig>()
+        .expect("valid config");
+    assert_eq!(migrate::migrate(&mut table).expect("migrates"), VERSION);
+
+    let mut invalid: Table = toml::from_str("recipients = [1]").expect("valid toml");
+    assert!(migrate::migrate(&mut invalid).is_err());
+}
+
+#[test]
+fn reject_unknown_and_newer() {
+    let unknown: Result<TomlConfig, _> = toml::from_str("version = 1\nidentiy = \"jane\"");
+    let error = unknown.expect_err("unknown key").to_string();
+    assert!(error.contains("identiy"), "{}", error);
+
+    let mut newer: Table = toml::from_str(&format!("version = {}", VERSION + 1)).expect("valid toml");
+    assert!(migrate::migrate(&mut newer).is_err());
+}
+
+#[test]
+fn identity_one_or_many() {
+    let one: TomlConfig = toml::from_str(r#"identity = "/keys/jane""#).expect("valid config");
+    assert_eq!(one.identities, vec![PathBuf::from("/keys/jane")]);
+    assert_eq!(toml::to_string(&one).expect("serializes").trim(), r#"identity = "/keys/jane""#);
+
+    let many: TomlConfig =
+        toml::from_str(r#"identity = ["/keys/jane", "/etc/ssh/ssh_host_ed25519_key"]"#)
+            .expect("valid config");
+    assert_eq!(
+        many.identities,
+        vec![PathBuf::from("/keys/jane"), PathBuf::from("/etc/ssh/ssh_host_ed25519_key")]
+    );
+}
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+#[cfg(test)]
+mod tests;
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, Context};
+
+use crate::{info, Result};
+
+/// Used when neither the config nor `$VISUAL` or `$EDITOR` name an editor.
+const FALLBACK: &str = "vi";
+
+/// GUI editors that return right away unless started with one of their flags,
+/// which would end the session before the file is edited.
+const WAIT_FLAGS: [(&str, &[&str]); 11] = [
+    ("code", &["--wait", "-w"]),
+    ("code-insiders", &["--wait", "-w"]),
+    ("codium", &["--wait", "-w"]),
+    ("subl", &["--wait", "-w"]),
+    ("zed", &["--wait", "-w"]),
+    ("atom", &["--wait", "-w"]),
+    ("mate", &["--wait", "-w"]),
+    ("gedit", &["--wait", "-w"]),
+    ("kate", &["--block", "-b"]),
+    ("gvim", &["--nofork", "-f"]),
+    ("mvim", &["--nofork", "-f"]),
+];
+
+/// `emacsclient` waits, unless told not to.
+const NO_WAIT_FLAGS: [(&str, &[&str]); 1] = [("emacsclient", &["--no-wait", "-n"])];
+
+pub trait EditorContext {
+    fn input(&self) -> PathBuf;
+}
+
+/// An editor command, like `code --wait`, and the arguments that follow the
+/// files.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Editor {
+    program: String,
+    args: Vec<String>,
+    args_after: Vec<String>,
+}
+
+impl Editor {
+    /// The configured editor, else `$VISUAL`, else `$EDITOR`, else `vi`.
+    /// Commands are split into words like a shell does, so they can carry
+    /// arguments.
+    pub fn resolve(configured: Option<String>, args_after: Vec<String>) -> Result<Self> {
+        let (origin, command) = [
+            ("the configured editor", configured),
+            ("$VISUAL", std::env::var("VISUAL").ok()),
+            ("$EDITOR", std::env::var("EDITOR").ok()),
+        ]
+        .into_iter()
+        .find_map(|(origin, command)| {
+            command
+                .filter(|command| !command.trim().is_empty())
+                .map(|command| (origin, command))
+        })
+        .unwrap_or(("the fallback", FALLBACK.to_string()));
+
+        let editor = Self::parse(&command, args_after)
+            .with_context(|| format!("Can't use {} {:?}", origin, command))?;
+        info!("Editor from {}: {:?}", origin, editor);
+        if let Some(warning) = editor.returns_early() {
+            eprintln!("warning: {}", warning);
+        }
+        Ok(editor)
+    }
+
+    fn parse(command: &str, args_after: Vec<String>) -> Result<Self> {
+        let mut words = shlex::split(command)
+            .ok_or(anyhow!("unbalanced quotes"))?
+            .into_iter();
+        let program = words.next().ok_or(anyhow!("no command"))?;
+        Ok(Self {
+            program,
+            args: words.collect(),
+            args_after,
+        })
+    }
+
+    /// A warning if the editor would return before the file is edited.
+    fn returns_early(&self) -> Option<String> {
+        let name = Path::new(&self.program).file_name()?.to_str()?;
+        let has = |flags: &[&str]| {
+            self.args
+                .iter()
+                .chain(&self.args_after)
+                .any(|arg| flags.contains(&arg.as_str()))
+        };
+
+        if let Some((_, flags)) = WAIT_FLAGS.iter().find(|(editor, _)| *editor == name)
+            && !has(flags)
+        {
+            return Some(format!(
+                "`{}` returns right away without `{}`, which ends the session before you edit \
+                 the file. Set the editor to `{} {}`",
+                name, flags[0], self.program, flags[0]
+            ));
+        }
+        if let Some((_, flags)) = NO_WAIT_FLAGS.iter().find(|(editor, _)| *editor == name)
+            && has(flags)
+        {
+            return Some(format!(
+                "`{}` returns right away with `{}`, which ends the session before you edit \
+                 the file",
+                name, flags[0]
+            ));
+        }
+        None
+    }
+
+    /// Opens the files together.
+    pub fn run(&self, inputs: &[PathBuf]) -> Result<()> {
+        Command::new(&self.program)
+            .args(&self.args)
+            .args(inputs)
+            .args(&self.args_after)
+            .status()
+            .context(format!("Failed to launch '{}'", self.program))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use super::Editor;
+
+fn editor(command: &str) -> Editor {
+    Editor::parse(command, vec![]).expect("parses")
+}
+
+#[test]
+fn splits_commands_like_a_shell() {
+    assert_eq!(
+        editor(r#"emacsclient -t --socket-name "/run/my emacs""#),
+        Editor {
+            program: "emacsclient".into(),
+            args: vec!["-t".into(), "--socket-name".into(), "/run/my emacs".into()],
+            args_after: vec![],
+        }
+    );
+    assert!(Editor::parse("code '--wait", vec![]).is_err());
+    assert!(Editor::parse("  ", vec![]).is_err());
+}
+
+#[test]
+fn warns_about_editors_that_return_right_away() {
+    assert!(editor("code").returns_early().is_some());
+    assert!(editor("/usr/bin/subl").returns_early().is_some());
+    assert!(editor("emacsclient -n").returns_early().is_some());
+
+    assert!(editor("code --wait").returns_early().is_none());
+    assert!(editor("gvim -f").returns_early().is_none());
+    assert!(editor("emacsclient -t").returns_early().is_none());
+    assert!(editor("nvim").returns_early().is_none());
+    let kate = Editor::parse("kate", vec!["--block".into()]).expect("parses");
+    assert!(kate.returns_early().is_none());
+}
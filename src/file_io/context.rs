@@ -1,12 +1,17 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
+use age::{secrecy::SecretString, Identity, Recipient};
 use blake3::Hash;
+use zeroize::Zeroizing;
 
 use crate::Result;
 
+pub type Recipients = Vec<Box<dyn Recipient + Send>>;
+pub type Identities = Vec<Box<dyn Identity + Send + Sync>>;
+
 pub trait ReadingContext {
     fn input(&self) -> Option<String>;
-    fn output(&mut self, content: Vec<u8>, hash: Hash);
+    fn output(&mut self, content: Zeroizing<Vec<u8>>, hash: Hash);
 }
 
 pub trait WritingContext {
@@ -19,18 +24,40 @@ pub trait DecryptionContext {
     fn input(&self) -> Option<String>;
     fn output(&self) -> Option<String>;
     fn identities(&self) -> Vec<String>;
-    fn result(&mut self, hash: Hash);
+    /// Whether the input is encrypted to a passphrase instead of identities.
+    fn passphrase_only(&self) -> bool;
+    /// Keeps the passphrase the input was decrypted with, to encrypt to it again.
+    fn unlocked(&mut self, passphrase: SecretString);
+    /// The decrypted content and its hash, and the hash of the encrypted input
+    /// to detect changes to it.
+    fn result(&mut self, content: Zeroizing<Vec<u8>>, hash: Hash, source_hash: Hash);
 }
 
-pub trait EncryptionContext {
-    fn input(&self) -> &[u8];
-    fn output(&self) -> Option<String>;
+pub trait RecipientContext {
     fn identities(&self) -> Vec<String>;
     fn recipients(&self) -> Vec<String>;
     fn recipients_files(&self) -> Vec<String>;
+    fn passphrase_only(&self) -> bool;
+    /// The passphrase the input was decrypted with, if any.
+    fn passphrase(&self) -> Option<SecretString>;
+    /// The recipients, and the identities that can decrypt what is encrypted
+    /// to them without asking, to check encrypted files with.
+    fn result(&mut self, recipients: Recipients, identities: Identities);
+}
+
+pub trait EncryptionContext {
+    fn input(&self) -> &[u8];
+    fn output(&self) -> Option<String>;
+    fn recipients(&self) -> &Recipients;
+    /// The identities the written output is decrypted with before it replaces
+    /// the file. Without any, it is not checked.
+    fn verifying_identities(&self) -> &Identities;
+    /// The hash of the output when it was last read or written. If the output
+    /// changed since, it is not overwritten. `None` overwrites it anyway.
+    fn source_hash(&self) -> Option<Hash>;
 }
 
 pub trait GenerationContext {
-    fn input(&self) -> Result<Vec<u8>>;
+    fn input(&self) -> Result<Zeroizing<Vec<u8>>>;
     fn output(&self) -> Option<String>;
 }
@@ -2,6 +2,9 @@
 
 use blake3::{Hash, Hasher};
 use std::io::{Read, Result, Write};
+use zeroize::Zeroizing;
+
+use super::secret;
 
 pub struct HashingWriter<W: Write> {
     inner: W,
@@ -44,26 +47,10 @@ impl<R: Read> HashingReader<R> {
         }
     }
 
-    pub fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
-        let mut tmp = [0u8; 8192]; // 8 KiB Puffer
-        let mut total = 0;
-
-        loop {
-            let n = self.read(&mut tmp)?;
-            if n == 0 {
-                break;
-            }
-            buf.extend_from_slice(&tmp[..n]);
-            total += n;
-        }
-
-        Ok(total)
-    }
-
-    pub fn read_all(&mut self) -> Result<(Vec<u8>, Hash)> {
-        let mut buf = Vec::new();
-        self.read_to_end(&mut buf)?;
-        Ok((buf, self.finalize()))
+    /// Reads to the end, into a buffer that is wiped when dropped.
+    pub fn read_all(&mut self) -> Result<(Zeroizing<Vec<u8>>, Hash)> {
+        let content = secret::read_to_end(self)?;
+        Ok((content, self.finalize()))
     }
 
     pub fn finalize(&self) -> Hash {
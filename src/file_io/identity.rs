@@ -7,57 +7,200 @@ use age::{
         file_io::{OutputFormat, OutputWriter},
         Passphrase, StdinGuard,
     },
-    secrecy::ExposeSecret,
+    secrecy::{ExposeSecret, SecretString},
     x25519::{self},
-    Encryptor, Identity, Recipient,
+    DecryptError, Encryptor, Identity, Recipient,
 };
+use age_core::format::{FileKey, Stanza};
 use anyhow::anyhow;
-use std::{io::Write, path::Path};
+use resolve_path::PathResolveExt;
+use std::{
+    cell::Cell,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{agent::Agent, debug, warn, Result};
+
+/// SSH key types age can encrypt to.
+const SSH_KEY_TYPES: [&str; 2] = ["ssh-ed25519", "ssh-rsa"];
+
+/// Comment in front of the public key in identity files.
+pub const PUBLIC_KEY_COMMENT: &str = "# public key:";
 
-use crate::Result;
+/// SSH keys offered in place of a new identity, in order of preference.
+const SSH_KEYS: [&str; 2] = ["~/.ssh/id_ed25519", "~/.ssh/id_rsa"];
 
 // TODO: watch again later
 pub fn create_identity(path: &Path, passphrase: bool) -> Result<()> {
-    let identity = path.to_str().map(String::from);
-    let mut output = OutputWriter::new(identity, false, OutputFormat::Text, 0o600, false)?;
+    let (content, _) = generate_identity()?;
+    let passphrase = passphrase.then(read_new_passphrase).transpose()?;
+    write_identity(path, &content, passphrase, false)
+}
+
+/// Generates an age key in the format of an identity file. Returns the content
+/// of the file and the public key.
+pub fn generate_identity() -> Result<(SecretString, String)> {
+    format_identity(x25519::Identity::generate())
+}
 
-    let sk = x25519::Identity::generate();
+/// Formats an age key as identity file. Returns the content of the file and the
+/// public key.
+pub fn format_identity(sk: x25519::Identity) -> Result<(SecretString, String)> {
     let pk = sk.to_public();
+    let public = pk.to_string();
 
-    if passphrase {
-        let encryptor = match cli_common::read_or_generate_passphrase() {
-            Ok(Passphrase::Typed(passphrase)) => Encryptor::with_user_passphrase(passphrase),
-            Ok(Passphrase::Generated(passphrase)) => {
-                println!("autogenerated_passphrase:");
-                println!("\t{}", passphrase.expose_secret());
-                Encryptor::with_user_passphrase(passphrase)
-            }
-            Err(e) => Err(anyhow!(e))?,
-        };
+    let mut content = Vec::new();
+    write_formated_keys(&mut content, pk, sk)?;
+    Ok((SecretString::from(String::from_utf8(content)?), public))
+}
+
+/// Writes an identity file, wrapped with a passphrase if one is given. An
+/// existing file is only replaced if `overwrite` is set, through a temporary
+/// file next to it, so the old keys aren't lost if writing fails.
+pub fn write_identity(
+    path: &Path,
+    content: &SecretString,
+    passphrase: Option<SecretString>,
+    overwrite: bool,
+) -> Result<()> {
+    let target = match (overwrite, path.file_name()) {
+        (true, Some(name)) => path.with_file_name(format!(".{}.new", name.to_string_lossy())),
+        _ => path.to_path_buf(),
+    };
+    if target != path {
+        let _ = std::fs::remove_file(&target);
+    }
+
+    let output = target.to_str().map(String::from);
+    let mut output = OutputWriter::new(output, false, OutputFormat::Text, 0o600, false)?;
+
+    if let Some(passphrase) = passphrase {
+        let encryptor = Encryptor::with_user_passphrase(passphrase);
         let mut output =
             encryptor.wrap_output(ArmoredWriter::wrap_output(output, Format::AsciiArmor)?)?;
-
-        write_formated_keys(&mut output, pk, sk)?;
+        output.write_all(content.expose_secret().as_bytes())?;
         output.finish().and_then(|armor| armor.finish())?;
     } else {
-        write_formated_keys(&mut output, pk, sk)?;
+        output.write_all(content.expose_secret().as_bytes())?;
+        output.flush()?;
     }
 
+    if target != path {
+        std::fs::rename(&target, path)?;
+    }
     Ok(())
 }
 
+/// Asks for a new passphrase, twice. If none is typed, one is generated and
+/// printed.
+pub fn read_new_passphrase() -> Result<SecretString> {
+    match cli_common::read_or_generate_passphrase() {
+        Ok(Passphrase::Typed(passphrase)) => Ok(passphrase),
+        Ok(Passphrase::Generated(passphrase)) => {
+            println!("autogenerated_passphrase:");
+            println!("\t{}", passphrase.expose_secret());
+            Ok(passphrase)
+        }
+        Err(e) => Err(anyhow!(e)),
+    }
+}
+
 fn write_formated_keys<W>(output: &mut W, pk: x25519::Recipient, sk: x25519::Identity) -> Result<()>
 where
     W: Write,
 {
-    writeln!(output, "# {}: {}", "public key", pk)?;
+    writeln!(output, "{} {}", PUBLIC_KEY_COMMENT, pk)?;
     writeln!(output, "{}", sk.to_string().expose_secret()).map_err(Into::into)
 }
 
-pub fn read_identities(identities: Vec<String>) -> Result<Vec<Box<dyn Identity>>> {
-    let mut stdin_guard = StdinGuard::new(false);
-    let identities = cli_common::read_identities(identities, None, &mut stdin_guard)?;
-    Ok(identities)
+/// Finds a private SSH key of the user that can be used as identity.
+pub fn find_ssh_key() -> Option<PathBuf> {
+    SSH_KEYS
+        .iter()
+        .map(PathBuf::from)
+        .find(|key| key.try_resolve().is_ok_and(|path| path.exists()))
+}
+
+/// The identities read from one identity file.
+pub struct IdentityFile {
+    pub path: String,
+    pub identities: Vec<Box<dyn Identity>>,
+}
+
+/// Reads each identity file on its own, so it is known which one decrypted a
+/// file. Files that can't be read are skipped, as long as one can be read: the
+/// same config can list keys of several machines.
+///
+/// Identity files held by the agent are tried through the agent first, so a
+/// passphrase is only asked for if the agent fails.
+pub fn read_identities(identities: Vec<String>, agent: Option<&Agent>) -> Result<Vec<IdentityFile>> {
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+
+    for path in identities {
+        let mut held: Vec<Box<dyn Identity>> = Vec::new();
+        if let Some(identity) = agent.and_then(|agent| agent.identity(&path)) {
+            held.push(Box::new(identity));
+        }
+
+        let mut stdin_guard = StdinGuard::new(false);
+        match cli_common::read_identities(vec![path.clone()], None, &mut stdin_guard) {
+            Ok(identities) => {
+                held.extend(identities);
+                files.push(IdentityFile { path, identities: held });
+            }
+            // Identities of other machines are expected to be missing, any
+            // other problem, like a mistyped passphrase, is reported.
+            Err(e) if !Path::new(&path).exists() => {
+                debug!("skipping identity {}: {}", path, e);
+                errors.push(format!("{}: {}", path, e));
+            }
+            Err(e) => {
+                warn!("skipping identity {}: {}", path, e);
+                eprintln!("warning: skipping identity {}: {}", path, e);
+                errors.push(format!("{}: {}", path, e));
+            }
+        }
+    }
+
+    if files.is_empty() {
+        return Err(anyhow!("No identity could be read:\n{}", errors.join("\n")));
+    }
+
+    Ok(files)
+}
+
+/// Splits the identity files into those that exist and those missing on this
+/// machine. Recipients are derived from the available ones only.
+pub fn available_identities(identities: Vec<String>) -> (Vec<String>, Vec<String>) {
+    identities
+        .into_iter()
+        .partition(|path| Path::new(path).exists())
+}
+
+/// Wraps an identity to record whether it unwrapped the file key.
+pub struct TrackedIdentity<'a> {
+    pub identity: &'a dyn Identity,
+    pub matched: &'a Cell<bool>,
+}
+
+impl Identity for TrackedIdentity<'_> {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        let result = self.identity.unwrap_stanza(stanza);
+        if matches!(result, Some(Ok(_))) {
+            self.matched.set(true);
+        }
+        result
+    }
+
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, DecryptError>> {
+        let result = self.identity.unwrap_stanzas(stanzas);
+        if matches!(result, Some(Ok(_))) {
+            self.matched.set(true);
+        }
+        result
+    }
 }
 
 pub fn read_recipients(
@@ -65,13 +208,46 @@ pub fn read_recipients(
     recipents_files: Vec<String>,
     identities: Vec<String>,
 ) -> Result<Vec<Box<dyn Recipient + Send>>> {
+    let mut recipents = recipents;
+    for file in recipents_files {
+        recipents.extend(read_recipients_file(&file)?);
+    }
+
     let mut stdin_guard = StdinGuard::new(false);
-    let recipients = cli_common::read_recipients(
-        recipents,
-        recipents_files,
-        identities,
-        None,
-        &mut stdin_guard,
-    )?;
+    let recipients =
+        cli_common::read_recipients(recipents, vec![], identities, None, &mut stdin_guard)?;
     Ok(recipients)
 }
+
+/// Reads a recipients file, which can also be an SSH `authorized_keys` file.
+/// Options in front of SSH keys are dropped and keys of types age can't encrypt
+/// to are skipped, so an existing `authorized_keys` can be used as is.
+fn read_recipients_file(path: &str) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Can't read recipients file {:?}: {}", path, e))?;
+
+    let mut recipients = Vec::new();
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if let Some(start) = tokens.iter().position(|t| SSH_KEY_TYPES.contains(t)) {
+            recipients.push(tokens[start..].join(" "));
+        } else if tokens.iter().any(|t| is_unsupported_ssh_key(t)) {
+            let message = format!("{}:{}: skipping unsupported SSH key", path, line_number + 1);
+            warn!("{}", message);
+            eprintln!("warning: {}", message);
+        } else {
+            recipients.push(line.to_string());
+        }
+    }
+
+    Ok(recipients)
+}
+
+fn is_unsupported_ssh_key(token: &str) -> bool {
+    token.starts_with("ecdsa-sha2-") || token.starts_with("sk-") || token == "ssh-dss"
+}
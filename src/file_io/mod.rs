@@ -3,26 +3,67 @@
 mod context;
 mod hash;
 mod identity;
+mod secret;
+mod unlock;
+
+#[cfg(test)]
+mod tests;
 
 use age::{
     armor::{ArmoredReader, ArmoredWriter, Format},
-    cli_common::file_io::{InputReader, OutputFormat, OutputWriter},
-    Decryptor, Encryptor, Identity,
+    cli_common::{self, file_io::InputReader},
+    scrypt,
+    stream::StreamReader,
+    DecryptError, Decryptor, Encryptor, Identity,
 };
 use anyhow::anyhow;
+use blake3::Hash;
 use hash::{HashingReader, HashingWriter};
 use std::{
+    cell::Cell,
+    fmt::{self, Display},
+    fs::{self, File, OpenOptions, Permissions},
     io::{self, Read, Write},
-    path::Path,
+    iter,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
 };
+use tempfile::NamedTempFile;
+use zeroize::Zeroizing;
 
-use crate::Result;
-use identity::{read_identities, read_recipients};
+use crate::{agent::Agent, debug, info, warn, Result};
+use identity::{available_identities, read_identities, read_recipients, TrackedIdentity};
 
 pub(crate) use context::{
-    DecryptionContext, EncryptionContext, GenerationContext, ReadingContext, WritingContext,
+    DecryptionContext, EncryptionContext, GenerationContext, Identities, ReadingContext,
+    RecipientContext, Recipients, WritingContext,
+};
+pub(crate) use identity::{
+    create_identity, find_ssh_key, format_identity, generate_identity, read_new_passphrase,
+    write_identity, PUBLIC_KEY_COMMENT,
 };
-pub(crate) use identity::create_identity;
+pub(crate) use secret::{protect_process, wipe, wipe_dir};
+pub(crate) use unlock::Unlocked;
+
+/// Mode of new encrypted files. A file that is replaced keeps its mode.
+const ENCRYPTED_MODE: u32 = 0o644;
+/// Mode of decrypted and generated files, which hold secrets.
+const PLAINTEXT_MODE: u32 = 0o600;
+
+/// The error of `encrypt` when the output changed since it was last read or
+/// written, e.g. by a `git pull`.
+#[derive(Debug)]
+pub struct SourceChanged {
+    pub source: String,
+}
+
+impl Display for SourceChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} changed on disk since it was decrypted", self.source)
+    }
+}
+
+impl std::error::Error for SourceChanged {}
 
 pub fn read<R: ReadingContext>(ctx: &mut R) -> Result<()> {
     let mut input = HashingReader::new(InputReader::new(ctx.input())?);
@@ -31,52 +72,288 @@ pub fn read<R: ReadingContext>(ctx: &mut R) -> Result<()> {
     Ok(())
 }
 
-pub fn encrypt<E: EncryptionContext>(ctx: &E) -> Result<()> {
-    let output = OutputWriter::new(ctx.output(), true, OutputFormat::Unknown, 0o666, false)?;
+/// Reads the recipients once, before the editor starts. Encrypting to them once
+/// runs any plugins up front, so their prompts reach the terminal and missing
+/// plugins are reported before anything is edited.
+pub fn resolve_recipients<R: RecipientContext>(ctx: &mut R) -> Result<()> {
+    if ctx.passphrase_only() {
+        let passphrase = match ctx.passphrase() {
+            Some(passphrase) => passphrase,
+            None => read_new_passphrase()?,
+        };
+        let identity = scrypt::Identity::new(passphrase.clone());
+        ctx.result(
+            vec![Box::new(scrypt::Recipient::new(passphrase))],
+            vec![Box::new(identity)],
+        );
+        return Ok(());
+    }
+
+    // The agent knows the public keys of the identities it holds, which saves
+    // decrypting them here. Other age and SSH identities are decrypted once and
+    // kept to check encrypted files with; plugin identities are left to age.
+    let agent = Agent::connect();
+    let mut keys = ctx.recipients();
+    let mut identities = Vec::new();
+    let mut verifying: Identities = Vec::new();
+    let (available, missing) = available_identities(ctx.identities());
+    for path in missing {
+        // Its public key can't be known here, so it is up to `recipients`.
+        warn!("identity {} not found, not adding it as recipient", path);
+        eprintln!(
+            "warning: identity {} is not on this machine, saves are only encrypted to it if its \
+             public key is in `recipients`",
+            path
+        );
+    }
+    for path in available {
+        if let Some(agent) = &agent
+            && let Some(recipients) = agent.recipients(&path)
+            && let Some(identity) = agent.identity(&path)
+        {
+            keys.extend_from_slice(recipients);
+            verifying.push(Box::new(identity));
+            continue;
+        }
+
+        match Unlocked::read(Path::new(&path), "Checking saves") {
+            Ok(unlocked) => {
+                keys.extend_from_slice(unlocked.recipients());
+                verifying.extend(unlocked.into_identities());
+            }
+            Err(e) => {
+                debug!("leaving identity {} to age: {}", path, e);
+                identities.push(path);
+            }
+        }
+    }
+
+    let recipients = read_recipients(keys, ctx.recipients_files(), identities)?;
+
+    let encryptor = Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as _))?;
+    encryptor.wrap_output(io::sink())?.finish()?;
+
+    ctx.result(recipients, verifying);
+    Ok(())
+}
+
+/// Encrypts the input to the output, unless the output changed since the
+/// context last saw it. The output is written to a temp file next to it,
+/// synced to disk and decrypted again, and only then renamed over the output,
+/// so a crash or a full disk never leaves it half written. Returns the hash of
+/// the written output.
+pub fn encrypt<E: EncryptionContext>(ctx: &E) -> Result<Hash> {
+    let path = output_path(ctx.output())?;
+    if let Some(expected) = ctx.source_hash()
+        && hash_file(&path)? != Some(expected)
+    {
+        return Err(SourceChanged {
+            source: path.display().to_string(),
+        }
+        .into());
+    }
+
+    let mut file = temp_file(&path, encrypted_mode(&path))?;
+
+    let output = HashingWriter::new(file.as_file_mut());
     let output = ArmoredWriter::wrap_output(output, Format::AsciiArmor)?;
-    let recipients = read_recipients(ctx.recipients(), ctx.recipients_files(), ctx.identities())?;
+    let recipients = ctx.recipients();
     let encryptor = Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as _))?;
     let mut output = encryptor.wrap_output(output)?;
 
     output.write_all(ctx.input())?;
-    output.finish().and_then(|armor| armor.finish())?;
+    let mut output = output.finish().and_then(|armor| armor.finish())?;
+    output.flush()?;
+    let hash = output.finalize();
+    file.as_file().sync_all()?;
+
+    verify(file.path(), ctx.input(), ctx.verifying_identities())
+        .map_err(|e| anyhow!("Not replacing {:?}: {}", path, e))?;
+    persist(file, &path)?;
+    Ok(hash)
+}
+
+/// Decrypts a written file with the identities, to prove it holds `content`.
+fn verify(path: &Path, content: &[u8], identities: &Identities) -> Result<()> {
+    if identities.is_empty() {
+        info!("No identity to check {:?} with", path);
+        return Ok(());
+    }
+
+    let identities = identities.iter().map(|identity| identity.as_ref() as &dyn Identity);
+    let decrypted = decrypt_with(path, identities)?
+        .ok_or(anyhow!("none of the identities can decrypt what was written"))?;
+    if decrypted.as_slice() != content {
+        return Err(anyhow!("what was written decrypts to something else"));
+    }
     Ok(())
 }
 
+/// The hash of a file, `None` if it doesn't exist.
+fn hash_file(path: &Path) -> Result<Option<Hash>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(blake3::hash(&content))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("Can't read {:?}: {}", path, e)),
+    }
+}
+
+/// Writes the generated file like `encrypt` writes the source: to a temp file
+/// that replaces it once it is on disk.
 pub fn generate<G: GenerationContext>(ctx: &G) -> Result<()> {
-    create_parent(ctx.output())?;
-    let mut output = OutputWriter::new(ctx.output(), true, OutputFormat::Text, 0o666, false)?;
-    output.write_all(&ctx.input()?)?;
-    output.flush()?;
-    Ok(())
+    let path = output_path(ctx.output())?;
+    let content = ctx.input()?;
+    create_parent(&path)?;
+
+    let mut file = temp_file(&path, PLAINTEXT_MODE)?;
+    file.write_all(&content)?;
+    file.as_file().sync_all()?;
+    persist(file, &path)
 }
 
 pub fn decrypt<D: DecryptionContext>(ctx: &mut D) -> Result<()> {
-    let input = InputReader::new(ctx.input())?;
-    let output = OutputWriter::new(ctx.output(), true, OutputFormat::Unknown, 0o666, false)?;
+    let (encrypted, source_hash) = HashingReader::new(InputReader::new(ctx.input())?).read_all()?;
+    let mut output = plaintext_file(ctx.output())?;
 
-    let decryptor = Decryptor::new_buffered(ArmoredReader::new(input))?;
-    let mut output = HashingWriter::new(output);
+    let decryptor = Decryptor::new_buffered(ArmoredReader::new(&encrypted[..]))?;
 
+    let mut input = if ctx.passphrase_only() {
+        let source = ctx.input().unwrap_or_default();
+        let passphrase = cli_common::read_secret(
+            &format!("Type passphrase for {}", source),
+            "Passphrase",
+            None,
+        )
+        .map_err(|e| anyhow!("Can't read passphrase: {}", e))?;
+
+        let identity = scrypt::Identity::new(passphrase.clone());
+        let input = decryptor
+            .decrypt(iter::once(&identity as &dyn Identity))
+            .map_err(|e| match e {
+                DecryptError::DecryptionFailed => anyhow!("Wrong passphrase for {:?}", source),
+                DecryptError::NoMatchingKeys => {
+                    anyhow!("{:?} is not encrypted to a passphrase", source)
+                }
+                e => e.into(),
+            })?;
+        ctx.unlocked(passphrase);
+        input
+    } else {
+        unwrap_identities(decryptor, ctx)?
+    };
+
+    let content = secret::read_to_end(&mut input)?;
+    output.write_all(&content)?;
+    output.flush()?;
+
+    let hash = blake3::hash(&content);
+    ctx.result(content, hash, source_hash);
+    Ok(())
+}
+
+/// Decrypts with the identities of the context, reporting which one matched.
+fn unwrap_identities<R: Read, D: DecryptionContext>(
+    decryptor: Decryptor<R>,
+    ctx: &D,
+) -> Result<StreamReader<R>> {
     let identities = ctx.identities();
     if identities.is_empty() {
         return Err(anyhow!("No identities"));
     }
-    let identities = read_identities(identities)?;
+    let configured = identities.len();
+    let files = read_identities(identities, Agent::connect().as_ref())?;
 
-    decryptor
-        .decrypt(identities.iter().map(|i| i.as_ref() as &dyn Identity))
-        .map_err(|e| e.into())
-        .and_then(|input| copy(input, &mut output))?;
+    let matched: Vec<Cell<bool>> = files.iter().map(|_| Cell::new(false)).collect();
+    let tracked: Vec<TrackedIdentity> = files
+        .iter()
+        .zip(&matched)
+        .flat_map(|(file, matched)| {
+            file.identities.iter().map(move |identity| TrackedIdentity {
+                identity: identity.as_ref(),
+                matched,
+            })
+        })
+        .collect();
 
-    let hash = output.finalize();
-    ctx.result(hash);
-    Ok(())
+    let input = decryptor
+        .decrypt(tracked.iter().map(|i| i as &dyn Identity))
+        .map_err(|e| match e {
+            DecryptError::NoMatchingKeys => anyhow!(
+                "None of the identities can decrypt {:?}: {}",
+                ctx.input().unwrap_or_default(),
+                files.iter().map(|f| f.path.as_str()).collect::<Vec<_>>().join(", ")
+            ),
+            e => e.into(),
+        })?;
+
+    if let Some(file) = files.iter().zip(&matched).find(|(_, m)| m.get()).map(|(f, _)| f) {
+        info!("Decrypted with identity {}", file.path);
+        if configured > 1 {
+            eprintln!("Decrypted with identity {}", file.path);
+        }
+    }
+
+    Ok(input)
+}
+
+/// Reads the identities once, to decrypt several files with them. Files
+/// encrypted to a passphrase are decrypted with the one typed for `source`.
+pub fn unlock_identities(
+    identities: Vec<String>,
+    passphrase_only: bool,
+    source: &Path,
+) -> Result<Vec<Box<dyn Identity>>> {
+    if passphrase_only {
+        let passphrase = cli_common::read_secret(
+            &format!("Type passphrase for {}", source.display()),
+            "Passphrase",
+            None,
+        )
+        .map_err(|e| anyhow!("Can't read passphrase: {}", e))?;
+        return Ok(vec![Box::new(scrypt::Identity::new(passphrase))]);
+    }
+
+    let files = read_identities(identities, Agent::connect().as_ref())?;
+    Ok(files.into_iter().flat_map(|file| file.identities).collect())
+}
+
+/// Decrypts `input` in memory. Returns `None` if none of the identities can
+/// decrypt it.
+pub fn decrypt_with<'a>(
+    input: &Path,
+    identities: impl Iterator<Item = &'a dyn Identity>,
+) -> Result<Option<Zeroizing<Vec<u8>>>> {
+    let input = InputReader::new(input.to_str().map(String::from))?;
+    let decryptor = Decryptor::new_buffered(ArmoredReader::new(input))?;
+
+    let mut reader = match decryptor.decrypt(identities) {
+        Ok(reader) => reader,
+        Err(DecryptError::NoMatchingKeys) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(secret::read_to_end(&mut reader)?))
+}
+
+/// Replaces an encrypted file with `content`, the way `encrypt` does.
+pub fn replace(path: &Path, content: &[u8]) -> Result<()> {
+    write_atomically(path, content, encrypted_mode(path))
+}
+
+/// Writes a file only its owner can read, the way `encrypt` does.
+pub fn write_private(path: &Path, content: &[u8]) -> Result<()> {
+    write_atomically(path, content, PLAINTEXT_MODE)
+}
+
+fn write_atomically(path: &Path, content: &[u8], mode: u32) -> Result<()> {
+    let mut file = temp_file(path, mode)?;
+    file.write_all(content)?;
+    file.as_file().sync_all()?;
+    persist(file, path)
 }
 
 pub fn write<W: WritingContext>(ctx: &mut W) -> Result<()> {
-    let output = OutputWriter::new(ctx.output(), true, OutputFormat::Text, 0o666, false)?;
-    let mut output = HashingWriter::new(output);
+    let mut output = HashingWriter::new(plaintext_file(ctx.output())?);
     output.write_all(ctx.input())?;
     output.flush()?;
     let hash = output.finalize();
@@ -84,18 +361,68 @@ pub fn write<W: WritingContext>(ctx: &mut W) -> Result<()> {
     Ok(())
 }
 
-fn copy<R: Read, W: Write>(mut input: R, mut output: W) -> Result<()> {
-    io::copy(&mut input, &mut output)?;
+fn output_path(output: Option<String>) -> Result<PathBuf> {
+    output
+        .map(PathBuf::from)
+        .ok_or(anyhow!("Can only write to files with UTF-8 paths"))
+}
 
-    Ok(())
+/// Creates or truncates a file for plaintext, readable by the user only.
+/// Written unbuffered, so no copy of the plaintext stays in a buffer.
+fn plaintext_file(output: Option<String>) -> Result<File> {
+    let path = output_path(output)?;
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(PLAINTEXT_MODE)
+        .open(&path)
+        .map_err(|e| anyhow!("Can't write {:?}: {}", path, e))?;
+    file.set_permissions(Permissions::from_mode(PLAINTEXT_MODE))?;
+    Ok(file)
 }
 
-// TODO: just for now
-fn create_parent(path: Option<String>) -> Result<()> {
-    let Some(path) = path else { return Ok(()) };
-    let Some(parent) = Path::new(&path).parent() else {
+fn create_parent(path: &Path) -> Result<()> {
+    let Some(parent) = path.parent() else {
         return Ok(());
     };
-    std::fs::create_dir_all(parent)?;
+    fs::create_dir_all(parent)?;
     Ok(())
 }
+
+/// The mode of an encrypted file: its current one, if it exists.
+fn encrypted_mode(path: &Path) -> u32 {
+    fs::metadata(path).map_or(ENCRYPTED_MODE, |metadata| metadata.permissions().mode() & 0o7777)
+}
+
+/// A hidden temp file next to `path`, like `.keys.age.Xb3kq1.tmp`, with the
+/// given mode. It is removed unless it is persisted.
+fn temp_file(path: &Path, mode: u32) -> Result<NamedTempFile> {
+    let name = path
+        .file_name()
+        .ok_or(anyhow!("{:?} has no file name", path))?
+        .to_string_lossy();
+    let file = tempfile::Builder::new()
+        .prefix(&format!(".{}.", name))
+        .suffix(".tmp")
+        .tempfile_in(parent_dir(path))
+        .map_err(|e| anyhow!("Can't create a temp file next to {:?}: {}", path, e))?;
+    file.as_file().set_permissions(Permissions::from_mode(mode))?;
+    Ok(file)
+}
+
+/// Renames the temp file over `path` and syncs the directory, so the rename
+/// is on disk too.
+fn persist(file: NamedTempFile, path: &Path) -> Result<()> {
+    file.persist(path)
+        .map_err(|e| anyhow!("Can't replace {:?}: {}", path, e.error))?;
+    File::open(parent_dir(path))?.sync_all()?;
+    Ok(())
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
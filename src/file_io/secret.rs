@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    fs::{self, OpenOptions, Permissions},
+    io::{self, Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+};
+use zeroize::Zeroizing;
+
+const CHUNK: usize = 8192;
+
+/// Reads to the end into a buffer that is wiped when dropped. The buffer grows
+/// by moving into a bigger one, which wipes the smaller one, so no copy of the
+/// content is left in freed memory.
+pub fn read_to_end<R: Read>(reader: &mut R) -> io::Result<Zeroizing<Vec<u8>>> {
+    let mut content = Zeroizing::new(Vec::with_capacity(CHUNK));
+    let mut chunk = Zeroizing::new([0u8; CHUNK]);
+    loop {
+        let n = match reader.read(&mut chunk[..]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if content.len() + n > content.capacity() {
+            let capacity = (content.len() + n).max(content.capacity() * 2);
+            let mut grown = Zeroizing::new(Vec::with_capacity(capacity));
+            grown.extend_from_slice(&content);
+            content = grown;
+        }
+        content.extend_from_slice(&chunk[..n]);
+    }
+    Ok(content)
+}
+
+/// Overwrites a file with zeros before removing it, so its plaintext doesn't
+/// stay in the blocks it used. Copies a copy-on-write file system keeps
+/// elsewhere are out of reach.
+pub fn wipe(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_file() {
+        // A file opened read-only can't be overwritten otherwise.
+        fs::set_permissions(path, Permissions::from_mode(0o600))?;
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        let zeros = [0u8; CHUNK];
+        let mut left = metadata.len();
+        while left > 0 {
+            let n = left.min(CHUNK as u64) as usize;
+            file.write_all(&zeros[..n])?;
+            left -= n as u64;
+        }
+        file.sync_all()?;
+    }
+    fs::remove_file(path)
+}
+
+/// Wipes the files of a dir, like the editor's swap files next to the
+/// decrypted file, and removes it.
+pub fn wipe_dir(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && !path.is_symlink() {
+            wipe_dir(&path)?;
+        } else {
+            wipe(&path)?;
+        }
+    }
+    fs::remove_dir(dir)
+}
+
+/// Keeps the plaintext in memory out of core dumps, and other processes of
+/// the user from reading it through ptrace. The editor, started with `exec`,
+/// is not affected.
+pub fn protect_process() {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) };
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::prctl(libc::PR_SET_DUMPABLE, 0)
+    };
+}
@@ -0,0 +1,286 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use age::{
+    scrypt,
+    secrecy::{ExposeSecret, SecretString},
+    x25519, Decryptor, Encryptor, Identity,
+};
+use blake3::Hash;
+use indoc::indoc;
+use std::{
+    env,
+    fs::{self, Permissions},
+    io::{Read, Write},
+    iter,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    process::Command,
+};
+
+use super::{
+    decrypt_with, encrypt,
+    secret::{read_to_end, wipe_dir},
+    identity::{available_identities, read_identities, read_recipients},
+    resolve_recipients, EncryptionContext, Identities, RecipientContext, Recipients,
+    SourceChanged,
+};
+
+const ED25519: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHsKLqeplhpW+uObz5dvMgjz1OxfM/XXUB+VHtZ6isGN alice@rust";
+const ECDSA: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBHFliOyIZs1gxGF3fmDxFykQhE88wy6AKDGFBfn0R6ZuvRmENABZQa9+pj9hMki+LX0qDJbmHTiWDbYv/cmFt/Q=";
+const X25519: &str = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+const STUB_RECIPIENT: &str = "age1stub1wd682c3qwfjkx6tsd9jkuaqewdtx9";
+const STUB_IDENTITY: &str = "AGE-PLUGIN-STUB-1WD682C3QD9JX2MN5D968JY53S0C";
+
+#[test]
+fn authorized_keys_as_recipients_file() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let file = dir.path().join("authorized_keys");
+    let content = format!(
+        indoc! {r#"
+            # team
+            {}
+            from="10.0.0.1",no-pty {}
+            {}
+
+            {}
+        "#},
+        ED25519, ED25519, ECDSA, X25519
+    );
+    std::fs::write(&file, content).expect("write");
+
+    let file = file.to_string_lossy().into_owned();
+    let recipients = read_recipients(vec![], vec![file], vec![]).expect("recipients");
+    assert_eq!(recipients.len(), 3);
+}
+
+#[test]
+fn skips_missing_and_broken_identities() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+    let key = x25519::Identity::generate();
+    fs::write(path("jane"), key.to_string().expose_secret()).expect("writes");
+    fs::write(path("broken"), "not an identity").expect("writes");
+    let configured = vec![path("host"), path("broken"), path("jane")];
+
+    let (available, missing) = available_identities(configured.clone());
+    assert_eq!(available, [path("broken"), path("jane")]);
+    assert_eq!(missing, [path("host")]);
+
+    let files = read_identities(configured, None).expect("identities");
+    let read: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+    assert_eq!(read, [path("jane")]);
+    assert!(read_identities(vec![path("broken")], None).is_err());
+}
+
+#[test]
+fn plugin_recipient_and_identity() {
+    // The stub plugin is built as an example, next to the test binary's `deps` dir.
+    let exe = env::current_exe().expect("test binary");
+    let examples = exe.ancestors().nth(2).expect("target dir").join("examples");
+    assert!(
+        examples.join("age-plugin-stub").exists(),
+        "stub plugin missing, run `cargo build --examples`"
+    );
+
+    // Plugins are found through `$PATH`, which can't be changed while other
+    // tests run, so the test runs again in a child process that has the stub.
+    let path = env::var_os("PATH").unwrap_or_default();
+    if !env::split_paths(&path).any(|dir| dir == examples) {
+        let path = env::join_paths(iter::once(examples).chain(env::split_paths(&path)))
+            .expect("joins PATH");
+        let output = Command::new(&exe)
+            .args(["--exact", "file_io::tests::plugin_recipient_and_identity"])
+            .env("PATH", path)
+            .output()
+            .expect("runs the test binary");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            output.status.success() && stdout.contains("1 passed"),
+            "{}{}",
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return;
+    }
+
+    let dir = tempfile::tempdir().expect("temp dir");
+    let identity = dir.path().join("identity");
+    std::fs::write(&identity, STUB_IDENTITY).expect("write");
+
+    let recipients =
+        read_recipients(vec![STUB_RECIPIENT.into()], vec![], vec![]).expect("recipients");
+    let encryptor = Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as _))
+        .expect("encryptor");
+    let mut encrypted = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut encrypted).expect("writer");
+    writer.write_all(b"{ a = 1; }").expect("write");
+    writer.finish().expect("finish");
+
+    let files = read_identities(vec![identity.to_string_lossy().into_owned()], None).expect("identities");
+    let identities = files.iter().flat_map(|file| &file.identities);
+    let mut decrypted = String::new();
+    Decryptor::new(&encrypted[..])
+        .expect("decryptor")
+        .decrypt(identities.map(|i| i.as_ref() as &dyn Identity))
+        .expect("decrypts")
+        .read_to_string(&mut decrypted)
+        .expect("read");
+    assert_eq!(decrypted, "{ a = 1; }");
+}
+
+/// A source encrypted to a passphrase only.
+struct PassphraseFile {
+    output: PathBuf,
+    passphrase: SecretString,
+    recipients: Recipients,
+    identities: Identities,
+    source_hash: Option<Hash>,
+}
+
+impl RecipientContext for PassphraseFile {
+    fn identities(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn recipients(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn recipients_files(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn passphrase_only(&self) -> bool {
+        true
+    }
+
+    fn passphrase(&self) -> Option<SecretString> {
+        Some(self.passphrase.clone())
+    }
+
+    fn result(&mut self, recipients: Recipients, identities: Identities) {
+        self.recipients = recipients;
+        self.identities = identities;
+    }
+}
+
+impl EncryptionContext for PassphraseFile {
+    fn input(&self) -> &[u8] {
+        b"{ a = 1; }"
+    }
+
+    fn output(&self) -> Option<String> {
+        self.output.to_str().map(String::from)
+    }
+
+    fn recipients(&self) -> &Recipients {
+        &self.recipients
+    }
+
+    fn verifying_identities(&self) -> &Identities {
+        &self.identities
+    }
+
+    fn source_hash(&self) -> Option<Hash> {
+        self.source_hash
+    }
+}
+
+#[test]
+fn passphrase_only() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let mut file = PassphraseFile {
+        output: dir.path().join("keys.age"),
+        passphrase: SecretString::from("correct horse".to_string()),
+        recipients: vec![],
+        identities: vec![],
+        source_hash: None,
+    };
+
+    // The passphrase typed when decrypting is reused, without identities.
+    resolve_recipients(&mut file).expect("recipients");
+    assert_eq!(file.recipients.len(), 1);
+    encrypt(&file).expect("encrypts");
+
+    let identity = scrypt::Identity::new(file.passphrase.clone());
+    let content = decrypt_with(&file.output, iter::once(&identity as &dyn Identity))
+        .expect("decrypts")
+        .expect("matches");
+    assert_eq!(*content, b"{ a = 1; }");
+
+    let wrong = scrypt::Identity::new(SecretString::from("wrong".to_string()));
+    assert!(decrypt_with(&file.output, iter::once(&wrong as &dyn Identity)).is_err());
+}
+
+#[test]
+fn refuses_to_overwrite_changed_source() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let mut file = PassphraseFile {
+        output: dir.path().join("keys.age"),
+        passphrase: SecretString::from("correct horse".to_string()),
+        recipients: vec![],
+        identities: vec![],
+        source_hash: None,
+    };
+    resolve_recipients(&mut file).expect("recipients");
+
+    let first = encrypt(&file).expect("encrypts");
+    file.source_hash = Some(first);
+    let second = encrypt(&file).expect("encrypts over its own save");
+
+    // Someone else wrote the source since.
+    file.source_hash = Some(first);
+    let error = encrypt(&file).expect_err("refuses");
+    assert!(error.is::<SourceChanged>());
+    assert_eq!(blake3::hash(&std::fs::read(&file.output).expect("reads")), second);
+}
+
+#[test]
+fn replaces_the_output_once_it_decrypts() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let mut file = PassphraseFile {
+        output: dir.path().join("keys.age"),
+        passphrase: SecretString::from("correct horse".to_string()),
+        recipients: vec![],
+        identities: vec![],
+        source_hash: None,
+    };
+    resolve_recipients(&mut file).expect("recipients");
+    assert_eq!(file.identities.len(), 1);
+
+    encrypt(&file).expect("encrypts");
+    let mode = |path: &PathBuf| fs::metadata(path).expect("metadata").permissions().mode() & 0o777;
+    assert_eq!(mode(&file.output), 0o644);
+
+    // A replaced file keeps its mode.
+    fs::set_permissions(&file.output, Permissions::from_mode(0o600)).expect("chmod");
+    encrypt(&file).expect("encrypts");
+    assert_eq!(mode(&file.output), 0o600);
+
+    // Output that doesn't decrypt is dropped and the file is left alone.
+    let written = fs::read(&file.output).expect("reads");
+    let wrong = scrypt::Identity::new(SecretString::from("wrong".to_string()));
+    file.identities = vec![Box::new(wrong)];
+    let error = encrypt(&file).expect_err("fails to verify");
+    assert!(format!("{:#}", error).contains("Not replacing"));
+    assert_eq!(fs::read(&file.output).expect("reads"), written);
+    assert_eq!(fs::read_dir(dir.path()).expect("lists").count(), 1);
+}
+
+#[test]
+fn reads_and_wipes_plaintext() {
+    let content = vec![7u8; 20_000];
+    assert_eq!(*read_to_end(&mut &content[..]).expect("reads"), content);
+
+    let dir = tempfile::tempdir().expect("temp dir");
+    let session = dir.path().join("session");
+    fs::create_dir(&session).expect("creates");
+    let file = session.join("keys.nix");
+    fs::write(&file, "{ password = \"secret\"; }").expect("writes");
+    fs::set_permissions(&file, Permissions::from_mode(0o400)).expect("chmod");
+    fs::write(session.join(".keys.nix.swp"), "secret").expect("writes");
+
+    wipe_dir(&session).expect("wipes");
+    assert!(!session.exists());
+}
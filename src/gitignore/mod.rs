@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+#[cfg(test)]
+mod tests;
+
+use anyhow::anyhow;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::{config::Gitignore, info, warn, Result};
+
+/// Makes sure a generated file inside a git work tree is ignored by git.
+///
+/// Files generated next to their sources are ignored one by one, otherwise the
+/// whole generated directory is.
+pub fn check(target: &Path, source_dir: &Path, policy: Gitignore) -> Result<()> {
+    let target = std::path::absolute(Path::new(".").join(target))?;
+    let source_dir = std::path::absolute(Path::new(".").join(source_dir))?;
+
+    let Some(work_tree) = work_tree(&target) else {
+        return Ok(());
+    };
+    if is_ignored(&work_tree, &target) {
+        return Ok(());
+    }
+
+    let dir = target
+        .parent()
+        .ok_or(anyhow!("{:?} has no parent", target))?;
+    let entry = if source_dir.starts_with(dir) {
+        format!("/{}", relative(&target, &work_tree)?)
+    } else {
+        format!("/{}/", relative(dir, &work_tree)?)
+    };
+
+    match policy {
+        Gitignore::Always => add_entry(&work_tree, &entry),
+        Gitignore::None => {
+            let message = format!(
+                "{:?} is inside the git work tree {:?} and not ignored. Generated files contain hashed secrets, add `{}` to .gitignore or set `gitignore = \"always\"`",
+                target, work_tree, entry
+            );
+            warn!("{}", message);
+            eprintln!("warning: {}", message);
+            Ok(())
+        }
+        Gitignore::Strict => Err(anyhow!(
+            "Refusing to generate {:?} inside the git work tree {:?}, it is not ignored. Add `{}` to .gitignore",
+            target,
+            work_tree,
+            entry
+        )),
+    }
+}
+
+fn work_tree(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+fn is_ignored(work_tree: &Path, path: &Path) -> bool {
+    Command::new("git")
+        .arg("-C")
+        .arg(work_tree)
+        .args(["check-ignore", "-q", "--no-index"])
+        .arg(path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn add_entry(work_tree: &Path, entry: &str) -> Result<()> {
+    let path = work_tree.join(".gitignore");
+    let content = std::fs::read_to_string(&path).unwrap_or_default();
+    if content.lines().any(|line| line.trim() == entry) {
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    if !content.is_empty() && !content.ends_with('\n') {
+        writeln!(file)?;
+    }
+    writeln!(file, "{}", entry)?;
+
+    info!("Added {} to {:?}", entry, path);
+    Ok(())
+}
+
+fn relative(path: &Path, work_tree: &Path) -> Result<String> {
+    path.strip_prefix(work_tree)
+        .map(|path| path.to_string_lossy().into_owned())
+        .map_err(|e| anyhow!("{:?} is not inside {:?}: {}", path, work_tree, e))
+}
@@ -0,0 +1,72 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{fs, process::Command};
+
+use super::{check, is_ignored};
+use crate::config::Gitignore;
+
+fn work_tree() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let status = Command::new("git")
+        .args(["init", "-q"])
+        .arg(dir.path())
+        .status()
+        .expect("runs git");
+    assert!(status.success());
+    fs::create_dir(dir.path().join("secrets")).expect("creates");
+    dir
+}
+
+#[test]
+fn always_ignores_the_file_or_the_generated_dir() {
+    let dir = work_tree();
+    let root = dir.path();
+    let secrets = root.join("secrets");
+
+    // Next to the source, only the file is ignored.
+    let next_to_source = secrets.join("keys.nix");
+    assert!(!is_ignored(root, &next_to_source));
+    check(&next_to_source, &secrets, Gitignore::Always).expect("adds an entry");
+    assert!(is_ignored(root, &next_to_source));
+    assert!(!is_ignored(root, &secrets.join("keys.age")));
+
+    let generated = root.join("generated/keys.nix");
+    check(&generated, &secrets, Gitignore::Always).expect("adds an entry");
+    assert!(is_ignored(root, &generated));
+    assert!(is_ignored(root, &root.join("generated/other.nix")));
+
+    // Entries are added once.
+    check(&generated, &secrets, Gitignore::Always).expect("checks");
+    let gitignore = fs::read_to_string(root.join(".gitignore")).expect("reads");
+    assert_eq!(gitignore, "/secrets/keys.nix\n/generated/\n");
+}
+
+#[test]
+fn none_only_warns() {
+    let dir = work_tree();
+    let target = dir.path().join("generated/keys.nix");
+
+    check(&target, &dir.path().join("secrets"), Gitignore::None).expect("warns");
+    assert!(!is_ignored(dir.path(), &target));
+    assert!(!dir.path().join(".gitignore").exists());
+}
+
+#[test]
+fn strict_refuses_files_that_are_not_ignored() {
+    let dir = work_tree();
+    let secrets = dir.path().join("secrets");
+    let target = dir.path().join("generated/keys.nix");
+
+    check(&target, &secrets, Gitignore::Strict).expect_err("refuses");
+    fs::write(dir.path().join(".gitignore"), "/generated/\n").expect("writes");
+    check(&target, &secrets, Gitignore::Strict).expect("is ignored");
+}
+
+#[test]
+fn outside_a_work_tree_anything_goes() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let target = dir.path().join("keys.nix");
+
+    check(&target, dir.path(), Gitignore::Strict).expect("not in git");
+    assert!(!dir.path().join(".gitignore").exists());
+}
@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+#[cfg(test)]
+mod tests;
+
+use anyhow::anyhow;
+use blake3::Hash;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    fs::{self, DirBuilder},
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config, file_io, info, Result};
+
+const PROJECTS_DIR: &str = "projects";
+const HISTORY_DIR: &str = "history";
+
+/// Earlier encrypted versions of a source, kept in the state dir of its
+/// project before a save replaces it. The oldest are dropped beyond `limit`.
+pub struct History {
+    dir: PathBuf,
+    limit: usize,
+}
+
+/// A kept version, named `<number>-<saved>.age`.
+pub struct Version {
+    /// Counts up with every version of the source, so it stays the same while
+    /// older versions are dropped.
+    pub number: usize,
+    /// Seconds since the Unix epoch.
+    pub saved: u64,
+    pub path: PathBuf,
+}
+
+/// An attribute that differs between two versions.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    Added(String),
+    Removed(String),
+    Changed(String),
+}
+
+impl History {
+    pub fn open(project_root: &Path, source: &Path, limit: usize) -> Result<Self> {
+        let root = std::path::absolute(project_root)?;
+        let source = std::path::absolute(source)?;
+        // Sources outside the project are kept by their absolute path.
+        let relative = source
+            .strip_prefix(&root)
+            .unwrap_or_else(|_| source.strip_prefix("/").unwrap_or(&source));
+
+        let name = root
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let id = blake3::hash(root.as_os_str().as_encoded_bytes()).to_hex();
+        let dir = config::state_dir()?
+            .join(PROJECTS_DIR)
+            .join(format!("{}-{}", name, &id[..8]))
+            .join(HISTORY_DIR)
+            .join(relative);
+        Ok(Self { dir, limit })
+    }
+
+    /// Keeps the source as the newest version, unless it is already.
+    pub fn snapshot(&self, source: &Path) -> Result<()> {
+        if self.limit == 0 || !source.exists() {
+            return Ok(());
+        }
+
+        let content = fs::read(source)?;
+        let versions = self.versions()?;
+        if let Some(newest) = versions.last()
+            && fs::read(&newest.path)? == content
+        {
+            return Ok(());
+        }
+
+        DirBuilder::new().recursive(true).mode(0o700).create(&self.dir)?;
+        let number = versions.last().map_or(1, |newest| newest.number + 1);
+        let path = self.dir.join(format!("{}-{}.age", number, now()));
+        file_io::write_private(&path, &content)?;
+        info!("Kept {:?} as {:?}", source, path);
+
+        let dropped = (versions.len() + 1).saturating_sub(self.limit);
+        for version in &versions[..dropped] {
+            fs::remove_file(&version.path)?;
+        }
+        Ok(())
+    }
+
+    /// The kept versions, oldest first.
+    pub fn versions(&self) -> Result<Vec<Version>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut versions = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let parsed = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".age"))
+                .and_then(|name| name.split_once('-'))
+                .and_then(|(number, saved)| Some((number.parse().ok()?, saved.parse().ok()?)));
+            if let Some((number, saved)) = parsed {
+                versions.push(Version {
+                    number,
+                    saved,
+                    path,
+                });
+            }
+        }
+        versions.sort_by_key(|version| version.number);
+        Ok(versions)
+    }
+
+    pub fn version(&self, number: usize) -> Result<Version> {
+        self.versions()?
+            .into_iter()
+            .find(|version| version.number == number)
+            .ok_or(anyhow!("No version {} in {:?}", number, self.dir))
+    }
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added(path) | Change::Removed(path) | Change::Changed(path) => path,
+        }
+    }
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added(path) => write!(f, "+ {}", path),
+            Change::Removed(path) => write!(f, "- {}", path),
+            Change::Changed(path) => write!(f, "~ {}", path),
+        }
+    }
+}
+
+/// The attributes added, removed or changed from `old` to `new`, outlined by
+/// `redact::outline`, ordered by path.
+pub fn diff(old: &BTreeMap<String, Hash>, new: &BTreeMap<String, Hash>) -> Vec<Change> {
+    let mut changes: Vec<Change> = new
+        .iter()
+        .filter_map(|(path, hash)| match old.get(path) {
+            None => Some(Change::Added(path.clone())),
+            Some(old) if old != hash => Some(Change::Changed(path.clone())),
+            Some(_) => None,
+        })
+        .chain(
+            old.keys()
+                .filter(|path| !new.contains_key(*path))
+                .map(|path| Change::Removed(path.clone())),
+        )
+        .collect();
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    changes
+}
+
+/// A local date and time like `2024-05-01 14:03`.
+pub fn format_time(secs: u64) -> String {
+    let Ok(time) = libc::time_t::try_from(secs) else {
+        return secs.to_string();
+    };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return secs.to_string();
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{fs, os::unix::fs::PermissionsExt};
+
+use super::{diff, Change, History};
+use crate::redact;
+
+#[test]
+fn keeps_the_latest_versions() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let source = dir.path().join("keys.age");
+    let history = History {
+        dir: dir.path().join("history"),
+        limit: 2,
+    };
+
+    for content in ["first", "second", "second", "third"] {
+        fs::write(&source, content).expect("writes");
+        history.snapshot(&source).expect("snapshots");
+    }
+
+    let versions = history.versions().expect("lists");
+    let numbers: Vec<usize> = versions.iter().map(|version| version.number).collect();
+    assert_eq!(numbers, [2, 3]);
+    assert_eq!(fs::read_to_string(&versions[0].path).expect("reads"), "second");
+    let newest = history.version(3).expect("finds");
+    assert_eq!(fs::read_to_string(newest.path).expect("reads"), "third");
+    assert!(history.version(1).is_err());
+
+    // Versions are private, and only they are left in the dir.
+    let mode = fs::metadata(&versions[0].path).expect("metadata").permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(fs::read_dir(&history.dir).expect("lists").count(), 2);
+}
+
+#[test]
+fn diff_shows_attribute_paths_only() {
+    let old = redact::outline(
+        br#"{ sha512, ... }: {
+          users.jane = { password = sha512 "old"; uid = 1000; };
+          users.bob.password = sha512 "bob";
+        }"#,
+    )
+    .expect("outlines");
+    let new = redact::outline(
+        br#"{ sha512, ... }: {
+          # comments and whitespace don't count
+          users.jane = {
+            password = sha512 "new";
+            uid = 1000;
+          };
+          users.eve.password = sha512 "eve";
+        }"#,
+    )
+    .expect("outlines");
+
+    assert_eq!(
+        diff(&old, &new),
+        [
+            Change::Removed("users.bob.password".into()),
+            Change::Added("users.eve.password".into()),
+            Change::Changed("users.jane.password".into()),
+        ]
+    );
+}
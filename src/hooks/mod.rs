@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+#[cfg(test)]
+mod tests;
+
+use anyhow::anyhow;
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+use strum_macros::AsRefStr;
+
+use crate::{
+    config::{Hooks, OnFailure},
+    debug, warn, Result,
+};
+
+#[derive(AsRefStr, Clone, Copy)]
+#[strum(serialize_all = "snake_case")]
+pub enum Stage {
+    PreEncrypt,
+    PostEncrypt,
+    PostGenerate,
+}
+
+pub trait HookContext {
+    fn hooks(&self) -> &Hooks;
+    fn source(&self) -> &Path;
+    fn target(&self) -> &Path;
+    fn project_root(&self) -> &Path;
+}
+
+/// Runs the commands of a stage with `sh -c` in the project root. A failing
+/// command stops the stage; whether that fails the caller depends on `on_failure`.
+pub fn run<H: HookContext>(ctx: &H, stage: Stage) -> Result<()> {
+    let hooks = ctx.hooks();
+    let commands = match stage {
+        Stage::PreEncrypt => &hooks.pre_encrypt,
+        Stage::PostEncrypt => &hooks.post_encrypt,
+        Stage::PostGenerate => &hooks.post_generate,
+    };
+
+    // Relative paths would be resolved against the project root by the hook.
+    let source = std::path::absolute(ctx.source())?;
+    let target = std::path::absolute(ctx.target())?;
+    let project_root = std::path::absolute(ctx.project_root())?;
+    for command in commands {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(&project_root)
+            .env("BOXEDNIX_SOURCE", &source)
+            .env("BOXEDNIX_TARGET", &target)
+            .env("BOXEDNIX_PROJECT_ROOT", &project_root)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| anyhow!("Failed to run {} hook `{}`: {}", stage.as_ref(), command, e))?;
+
+        if output.status.success() {
+            debug!("{} hook `{}` succeeded", stage.as_ref(), command);
+            continue;
+        }
+
+        let error = anyhow!(
+            "{} hook `{}` failed ({}): {}",
+            stage.as_ref(),
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+
+        match hooks.on_failure {
+            OnFailure::Fatal => return Err(error),
+            OnFailure::Warn => {
+                warn!("{}", error);
+                eprintln!("warning: {}", error);
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
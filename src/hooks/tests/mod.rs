@@ -0,0 +1,78 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use super::{run, HookContext, Stage};
+use crate::config::{Hooks, OnFailure};
+
+struct Hooked {
+    hooks: Hooks,
+    source: PathBuf,
+    target: PathBuf,
+    project_root: PathBuf,
+}
+
+impl HookContext for Hooked {
+    fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    fn source(&self) -> &Path {
+        &self.source
+    }
+
+    fn target(&self) -> &Path {
+        &self.target
+    }
+
+    fn project_root(&self) -> &Path {
+        &self.project_root
+    }
+}
+
+#[test]
+fn runs_in_the_project_root_with_absolute_paths() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let root = dir.path().canonicalize().expect("canonical");
+    let ctx = Hooked {
+        hooks: Hooks {
+            pre_encrypt: vec![
+                r#"printf '%s\n' "$(pwd -P)" "$BOXEDNIX_SOURCE" "$BOXEDNIX_TARGET" \
+                    "$BOXEDNIX_PROJECT_ROOT" > seen.txt"#
+                    .into(),
+            ],
+            post_encrypt: vec!["exit 3".into()],
+            on_failure: OnFailure::Fatal,
+            ..Default::default()
+        },
+        source: root.join("keys.age"),
+        // Relative to where `bx` runs, like a relative `generated_dir`.
+        target: PathBuf::from("generated/keys.nix"),
+        project_root: root.clone(),
+    };
+
+    run(&ctx, Stage::PreEncrypt).expect("runs");
+    let cwd = env::current_dir().expect("cwd");
+    let seen = fs::read_to_string(root.join("seen.txt")).expect("hook wrote");
+    let expected = [
+        root.display().to_string(),
+        root.join("keys.age").display().to_string(),
+        cwd.join("generated/keys.nix").display().to_string(),
+        root.display().to_string(),
+    ];
+    assert_eq!(seen.lines().collect::<Vec<_>>(), expected);
+
+    let error = run(&ctx, Stage::PostEncrypt).expect_err("fails");
+    assert!(error.to_string().contains("exit 3"), "{}", error);
+    let ctx = Hooked {
+        hooks: Hooks {
+            on_failure: OnFailure::Warn,
+            ..ctx.hooks.clone()
+        },
+        ..ctx
+    };
+    run(&ctx, Stage::PostEncrypt).expect("only warns");
+}
@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use age::{
+    secrecy::{ExposeSecret, SecretString},
+    x25519,
+};
+use anyhow::anyhow;
+use bip39::Mnemonic;
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use std::{
+    fmt::Write as _,
+    io::{self, IsTerminal, Read},
+    path::Path,
+};
+use zeroize::Zeroizing;
+
+use super::{decode_secret, encode_secret, identity_from_secret, secret_key, SECRET_KEY_PREFIX};
+use crate::{
+    file_io::{self, Unlocked, PUBLIC_KEY_COMMENT},
+    Result,
+};
+
+/// Words per line of a paper backup.
+const WORDS_PER_LINE: usize = 6;
+
+/// Prints an identity as a paper backup or, with `unprotected`, the identity
+/// file without its passphrase. The unprotected file is only written to a file
+/// or a pipe, never shown on the terminal.
+pub fn export(path: &Path, paper: bool, unprotected: bool) -> Result<()> {
+    if !paper && !unprotected {
+        return Err(anyhow!("Choose `--paper` or `--unprotected`"));
+    }
+    if unprotected && io::stdout().is_terminal() {
+        return Err(anyhow!(
+            "Refusing to print the unprotected key to the terminal, redirect it to a file"
+        ));
+    }
+
+    let unlocked = Unlocked::read(path, "`bx identity export`")?;
+    if paper {
+        let secret = secret_key(&unlocked)?;
+        print!("{}", render_paper(&secret, &unlocked.recipients()[0])?.as_str());
+    } else {
+        print!("{}", unlocked.content().expose_secret());
+    }
+    Ok(())
+}
+
+/// Writes an identity file read from stdin, either an identity file or, with
+/// `paper`, a paper backup.
+pub fn import(path: &Path, paper: bool, passphrase: bool) -> Result<()> {
+    if path.exists() {
+        return Err(anyhow!("{:?} already exists", path));
+    }
+    if io::stdin().is_terminal() && paper {
+        eprintln!("Enter the paper backup, with its public key line, then Ctrl-D:");
+    } else if io::stdin().is_terminal() {
+        eprintln!("Enter the identity file, then Ctrl-D:");
+    }
+
+    let mut text = Zeroizing::new(String::new());
+    io::stdin().read_to_string(&mut text)?;
+    let (content, public) = if paper {
+        parse_paper(&text)?
+    } else {
+        parse_identity(&text)?
+    };
+
+    let passphrase = passphrase.then(file_io::read_new_passphrase).transpose()?;
+    file_io::write_identity(path, &content, passphrase, false)?;
+    println!("Imported {} to {}", public, path.display());
+    Ok(())
+}
+
+/// Renders an age key for printing: the public key, the secret as BIP39 words
+/// and a QR code of the secret key.
+pub fn render_paper(secret: &[u8], public: &str) -> Result<Zeroizing<String>> {
+    let mnemonic = Mnemonic::from_entropy(secret)?;
+    let words: Vec<&str> = mnemonic.words().collect();
+
+    let mut paper = Zeroizing::new(String::new());
+    writeln!(paper, "# boxednix paper backup, restore with `bx identity import --paper`")?;
+    writeln!(paper, "{} {}\n", PUBLIC_KEY_COMMENT, public)?;
+    for (line, chunk) in words.chunks(WORDS_PER_LINE).enumerate() {
+        let mut text = Zeroizing::new(String::new());
+        for (column, word) in chunk.iter().enumerate() {
+            write!(text, "{:>2}. {:<9}", line * WORDS_PER_LINE + column + 1, word)?;
+        }
+        writeln!(paper, "{}", text.trim_end())?;
+    }
+
+    let key = encode_secret(secret)?;
+    let qr = QrCode::new(key.as_bytes())?;
+    writeln!(paper, "\n{}", qr.render::<Dense1x2>().quiet_zone(true).build())?;
+    Ok(paper)
+}
+
+/// Rebuilds an identity file from a paper backup, from its words or from the
+/// scanned secret key. The key has to match the public key of the backup.
+/// Returns the content of the file and the public key.
+pub fn parse_paper(text: &str) -> Result<(SecretString, String)> {
+    let mut public = None;
+    let mut key = None;
+    let mut words = Zeroizing::new(Vec::new());
+
+    for line in text.lines().map(str::trim) {
+        if let Some(line) = line.strip_prefix(PUBLIC_KEY_COMMENT) {
+            public = Some(line.trim());
+        } else if line.to_uppercase().starts_with(SECRET_KEY_PREFIX) {
+            key = Some(line);
+        } else if !line.starts_with('#') {
+            // Skips the numbers in front of the words and the QR code.
+            let tokens = line
+                .split_whitespace()
+                .filter(|token| token.chars().all(|c| c.is_ascii_alphabetic()));
+            words.extend(tokens.map(str::to_lowercase));
+        }
+    }
+
+    let public = public.ok_or(anyhow!(
+        "The backup has no `{}` line to verify the key against",
+        PUBLIC_KEY_COMMENT
+    ))?;
+    let secret = match key {
+        Some(key) => decode_secret(key)?,
+        None => {
+            let mnemonic = Mnemonic::parse_normalized(&Zeroizing::new(words.join(" ")))
+                .map_err(|e| anyhow!("Invalid words: {}", e))?;
+            Zeroizing::new(mnemonic.to_entropy())
+        }
+    };
+
+    let (content, rebuilt) = identity_from_secret(&secret)?;
+    if rebuilt != public {
+        return Err(anyhow!(
+            "The backup rebuilds {}, not its public key {}",
+            rebuilt,
+            public
+        ));
+    }
+    Ok((content, rebuilt))
+}
+
+/// Checks that an identity file holds age keys. Returns it with the public key
+/// of its first key.
+fn parse_identity(text: &str) -> Result<(SecretString, String)> {
+    let mut public = None;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let identity: x25519::Identity = line
+            .parse()
+            .map_err(|_| anyhow!("Only age identity files can be imported"))?;
+        public.get_or_insert(identity.to_public().to_string());
+    }
+
+    let public = public.ok_or(anyhow!("No age key given"))?;
+    Ok((SecretString::from(text.to_string()), public))
+}
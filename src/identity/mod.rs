@@ -0,0 +1,106 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+mod backup;
+mod rotate;
+mod shamir;
+
+#[cfg(test)]
+mod tests;
+
+use age::{
+    secrecy::{ExposeSecret, SecretString},
+    x25519,
+};
+use anyhow::anyhow;
+use bech32::{FromBase32, ToBase32, Variant};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+use crate::{file_io::{self, Unlocked}, Result};
+
+pub use backup::{export, import};
+pub use rotate::rotate;
+pub use shamir::{combine, split};
+
+/// Prefix of age secret keys, the bech32 human readable part in upper case.
+const SECRET_KEY_PREFIX: &str = "AGE-SECRET-KEY-";
+
+/// Prints the public keys of an identity file, one per line.
+pub fn show_public(path: &Path) -> Result<()> {
+    for recipient in Unlocked::read(path, "`bx identity show-public`")?.recipients() {
+        println!("{}", recipient);
+    }
+    Ok(())
+}
+
+/// Adds, changes or removes the passphrase that protects an age identity file.
+pub fn passphrase(path: &Path, remove: bool) -> Result<()> {
+    let unlocked = Unlocked::read(path, "`bx identity passphrase`")?;
+    if unlocked.ssh {
+        return Err(anyhow!(
+            "{:?} is an SSH key, change its passphrase with `ssh-keygen -p`",
+            path
+        ));
+    }
+    if remove && !unlocked.protected {
+        println!("{} has no passphrase", unlocked.path.display());
+        return Ok(());
+    }
+
+    let passphrase = (!remove).then(file_io::read_new_passphrase).transpose()?;
+    file_io::write_identity(&unlocked.path, unlocked.content(), passphrase, true)?;
+
+    match (remove, unlocked.protected) {
+        (true, _) => println!("Removed the passphrase of {}", unlocked.path.display()),
+        (false, true) => println!("Changed the passphrase of {}", unlocked.path.display()),
+        (false, false) => println!("Added a passphrase to {}", unlocked.path.display()),
+    }
+    Ok(())
+}
+
+/// The raw x25519 secret of an identity file holding a single age key.
+fn secret_key(unlocked: &Unlocked) -> Result<Zeroizing<Vec<u8>>> {
+    if unlocked.ssh {
+        return Err(anyhow!(
+            "{:?} is an SSH key, back it up with your SSH tooling",
+            unlocked.path
+        ));
+    }
+
+    let keys: Vec<&str> = unlocked
+        .content()
+        .expose_secret()
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with(SECRET_KEY_PREFIX))
+        .collect();
+    let [key] = keys[..] else {
+        return Err(anyhow!("{:?} has to hold exactly one age key", unlocked.path));
+    };
+
+    decode_secret(key)
+}
+
+/// Decodes an `AGE-SECRET-KEY-1...` string to the raw x25519 secret.
+fn decode_secret(key: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let key = Zeroizing::new(key.to_lowercase());
+    let (_, data, _) = bech32::decode(&key).map_err(|e| anyhow!("Invalid age key: {}", e))?;
+    let secret = Vec::<u8>::from_base32(&data).map_err(|e| anyhow!("Invalid age key: {}", e))?;
+    Ok(Zeroizing::new(secret))
+}
+
+/// Encodes a raw x25519 secret as `AGE-SECRET-KEY-1...` string.
+fn encode_secret(secret: &[u8]) -> Result<Zeroizing<String>> {
+    let hrp = SECRET_KEY_PREFIX.to_lowercase();
+    let key = Zeroizing::new(bech32::encode(&hrp, secret.to_base32(), Variant::Bech32)?);
+    Ok(Zeroizing::new(key.to_uppercase()))
+}
+
+/// Rebuilds an identity file from a raw x25519 secret. Returns the content of
+/// the file and the public key.
+fn identity_from_secret(secret: &[u8]) -> Result<(SecretString, String)> {
+    let sk: x25519::Identity = encode_secret(secret)?
+        .parse()
+        .map_err(|e| anyhow!("Invalid age key: {}", e))?;
+    file_io::format_identity(sk)
+}
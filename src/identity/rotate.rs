@@ -0,0 +1,312 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use age::secrecy::SecretString;
+use anyhow::anyhow;
+use blake3::Hash;
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fs,
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+use crate::{
+    agent::Agent,
+    config::{self, Config, Recipient},
+    debug,
+    file_io::{self, EncryptionContext, Identities, RecipientContext, Recipients, Unlocked},
+    history::History,
+    session, Result,
+};
+
+/// Extension of encrypted files.
+const ENCRYPTED_EXTENSION: &str = "age";
+
+/// Configs are loaded for a source, whose name doesn't matter here.
+const ROTATE_SOURCE: &str = "rotate.age";
+
+/// Replaces an age identity with a new key. Every file of the project the old
+/// key can decrypt is decrypted and its recipients are read first, so nothing
+/// is changed if one of them fails. So are the kept versions of those files and
+/// the journals of interrupted sessions editing them. Then the new key is
+/// written, keeping the old one as `<identity>.old`, the old public key is
+/// replaced in the configs and recipients files of the project and the
+/// projects nested in it, and each file is encrypted again to the recipients
+/// of its own project.
+pub fn rotate(cwd: &Path, path: &Path) -> Result<()> {
+    let project_root = config::find_project_root(cwd).ok_or(anyhow!(
+        "Config file not found. Current working dir: {:?}",
+        cwd
+    ))?;
+    let project_root = std::path::absolute(project_root)?;
+
+    // The agent would keep handing out the old public key.
+    let held = Agent::connect()
+        .is_some_and(|agent| path.to_str().and_then(|p| agent.recipients(p)).is_some());
+    if held {
+        return Err(anyhow!(
+            "{:?} is held by the agent, stop it with `bx agent --stop` first",
+            path
+        ));
+    }
+
+    let old = Unlocked::read(path, "`bx identity rotate`")?;
+    if old.ssh {
+        return Err(anyhow!(
+            "{:?} is an SSH key, rotate it with `ssh-keygen` and update the recipients",
+            path
+        ));
+    }
+
+    let backup = backup_path(&old.path);
+    if backup.exists() {
+        return Err(anyhow!("{:?} already exists, move it away first", backup));
+    }
+
+    // Generated files and kept versions aren't sources of the project.
+    let config = config::load(&project_root, &project_root.join(ROTATE_SOURCE))?;
+    refuse_passphrase_only(&config, &project_root)?;
+    let skip = [config.target_dir, config::state_dir()?];
+
+    let mut files = Vec::new();
+    for source in encrypted_files(&project_root, &skip)? {
+        let dir = dir_of(&source);
+        let config = config::load(&dir, &source)?;
+        for version in History::open(&config.project_root, &source, config.history)?.versions()? {
+            files.push((version.path, dir.clone()));
+        }
+        files.push((source, dir));
+    }
+    for session in session::interrupted()? {
+        if session.meta.source.starts_with(&project_root) {
+            let dir = dir_of(&session.meta.source);
+            files.extend(session.entries.into_iter().map(|entry| (entry, dir.clone())));
+        }
+    }
+
+    // Files are grouped by their dir, so the recipients of a config are only
+    // resolved once.
+    let mut groups: BTreeMap<PathBuf, Rekey> = BTreeMap::new();
+    for (source, dir) in files {
+        let content = match file_io::decrypt_with(&source, old.identities())
+            .map_err(|e| anyhow!("Can't decrypt {:?}: {}", source, e))?
+        {
+            Some(content) => content,
+            None => {
+                debug!("{:?} is not encrypted to {:?}, skipping it", source, old.path);
+                continue;
+            }
+        };
+
+        let rekey = match groups.entry(dir) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let rekey = Rekey::new(entry.key().clone())?;
+                entry.insert(rekey)
+            }
+        };
+        rekey.files.push((source, content));
+    }
+    for rekey in groups.values_mut() {
+        file_io::resolve_recipients(rekey)?;
+    }
+
+    // Nested projects have configs and recipients files of their own.
+    let mut recipient_sources = config::recipient_sources(cwd)?;
+    for dir in groups.keys() {
+        for source in config::recipient_sources(dir)? {
+            if !recipient_sources.contains(&source) {
+                recipient_sources.push(source);
+            }
+        }
+    }
+
+    let (content, public) = file_io::generate_identity()?;
+    let passphrase = old.protected.then(file_io::read_new_passphrase).transpose()?;
+    fs::copy(&old.path, &backup)?;
+    file_io::write_identity(&old.path, &content, passphrase, true)?;
+    println!(
+        "New key written to {}, the old one is kept at {}",
+        old.path.display(),
+        backup.display()
+    );
+
+    for source in recipient_sources {
+        let text = fs::read_to_string(&source)
+            .map_err(|e| anyhow!("Can't read {:?}: {}", source, e))?;
+        let replaced = old
+            .recipients()
+            .iter()
+            .fold(text.clone(), |text, key| text.replace(key, &public));
+        if replaced != text {
+            fs::write(&source, replaced)?;
+            println!("Updated recipients in {}", source.display());
+        }
+    }
+
+    // Read again, now that the identity and the configs name the new key.
+    for (dir, rekey) in groups {
+        let mut fresh = Rekey::new(dir)?;
+        file_io::resolve_recipients(&mut fresh)?;
+        for (source, content) in &rekey.files {
+            file_io::encrypt(&RekeyFile {
+                rekey: &fresh,
+                source,
+                content,
+            })?;
+            println!("Rekeyed {}", source.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".old");
+    PathBuf::from(backup)
+}
+
+/// Files encrypted to a passphrase only would have to be rekeyed with a new
+/// passphrase each, which isn't what rotating an identity means.
+fn refuse_passphrase_only(config: &Config, dir: &Path) -> Result<()> {
+    if config.passphrase_only {
+        return Err(anyhow!(
+            "The files in {:?} are encrypted to a passphrase only, not to an identity, so there \
+             is no key to rotate",
+            dir
+        ));
+    }
+    Ok(())
+}
+
+fn dir_of(source: &Path) -> PathBuf {
+    source.parent().unwrap_or(Path::new("/")).to_path_buf()
+}
+
+/// Finds the encrypted files of a project, skipping hidden directories and
+/// the ones in `skip`. Symlinks are not followed.
+pub(super) fn encrypted_files(root: &Path, skip: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let path = entry.path();
+            let hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if file_type.is_dir() {
+                if !hidden && !skip.contains(&path) {
+                    dirs.push(path);
+                }
+            } else if file_type.is_file()
+                && path.extension().is_some_and(|ext| ext == ENCRYPTED_EXTENSION)
+            {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// The decrypted files of a dir, encrypted again to the recipients of its
+/// config.
+struct Rekey {
+    files: Vec<(PathBuf, Zeroizing<Vec<u8>>)>,
+    identities: Vec<PathBuf>,
+    recipients: Vec<Recipient>,
+    resolved_recipients: Recipients,
+    verifying_identities: Identities,
+}
+
+/// A file of a `Rekey`.
+struct RekeyFile<'a> {
+    rekey: &'a Rekey,
+    source: &'a Path,
+    content: &'a [u8],
+}
+
+impl Rekey {
+    /// Takes the identities and recipients from the config of the project the
+    /// dir is in, which may be nested in the one `rotate` runs in.
+    fn new(dir: PathBuf) -> Result<Self> {
+        let config = config::load(&dir, &dir.join(ROTATE_SOURCE))?;
+        refuse_passphrase_only(&config, &dir)?;
+        Ok(Self {
+            files: Vec::new(),
+            identities: config.identities,
+            recipients: config.recipients,
+            resolved_recipients: Vec::new(),
+            verifying_identities: Vec::new(),
+        })
+    }
+}
+
+impl RecipientContext for Rekey {
+    fn identities(&self) -> Vec<String> {
+        self.identities
+            .iter()
+            .filter_map(|path| path.to_str().map(String::from))
+            .collect()
+    }
+
+    fn recipients(&self) -> Vec<String> {
+        self.recipients
+            .iter()
+            .filter_map(|recipient| match recipient {
+                Recipient::Key(key) => Some(key.clone()),
+                Recipient::File(_) => None,
+            })
+            .collect()
+    }
+
+    fn recipients_files(&self) -> Vec<String> {
+        self.recipients
+            .iter()
+            .filter_map(|recipient| match recipient {
+                Recipient::File(path) => path.to_str().map(String::from),
+                Recipient::Key(_) => None,
+            })
+            .collect()
+    }
+
+    fn passphrase_only(&self) -> bool {
+        false
+    }
+
+    fn passphrase(&self) -> Option<SecretString> {
+        None
+    }
+
+    fn result(&mut self, recipients: Recipients, identities: Identities) {
+        self.resolved_recipients = recipients;
+        self.verifying_identities = identities;
+    }
+}
+
+impl EncryptionContext for RekeyFile<'_> {
+    fn input(&self) -> &[u8] {
+        self.content
+    }
+
+    fn output(&self) -> Option<String> {
+        self.source.to_str().map(String::from)
+    }
+
+    fn recipients(&self) -> &Recipients {
+        &self.rekey.resolved_recipients
+    }
+
+    fn verifying_identities(&self) -> &Identities {
+        &self.rekey.verifying_identities
+    }
+
+    fn source_hash(&self) -> Option<Hash> {
+        None
+    }
+}
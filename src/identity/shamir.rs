@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use age::secrecy::SecretString;
+use anyhow::anyhow;
+use bech32::{FromBase32, ToBase32, Variant};
+use blahaj::Sharks;
+use std::{
+    io::{self, BufRead, IsTerminal},
+    path::Path,
+};
+use zeroize::Zeroizing;
+
+use super::{identity_from_secret, secret_key};
+use crate::{
+    file_io::{self, Unlocked},
+    Result,
+};
+
+/// Human readable part of the bech32 encoded shares.
+const SHARE_HRP: &str = "bxshare";
+
+/// Length of the key id in a share, taken from the hash of the public key.
+const ID_LEN: usize = 4;
+
+/// One Shamir share of an age key. Besides the share itself, it carries the
+/// threshold and an id of the public key, so shares of different keys or
+/// splits can't be mixed up.
+pub struct Share {
+    threshold: u8,
+    id: [u8; ID_LEN],
+    share: blahaj::Share,
+}
+
+impl Share {
+    /// Encodes the share as bech32, which detects typos when it is typed in.
+    pub fn encode(&self) -> Result<String> {
+        let mut data = Zeroizing::new(vec![self.threshold]);
+        data.extend_from_slice(&self.id);
+        data.extend(Vec::from(&self.share));
+        Ok(bech32::encode(SHARE_HRP, data.to_base32(), Variant::Bech32m)?.to_uppercase())
+    }
+
+    pub fn decode(text: &str) -> Result<Self> {
+        let (hrp, data, variant) = bech32::decode(&text.to_lowercase())
+            .map_err(|e| anyhow!("Invalid share: {}", e))?;
+        if hrp != SHARE_HRP || variant != Variant::Bech32m {
+            return Err(anyhow!("Not a boxednix share"));
+        }
+
+        let data = Zeroizing::new(Vec::<u8>::from_base32(&data)?);
+        if data.len() < ID_LEN + 3 {
+            return Err(anyhow!("Share is too short"));
+        }
+        let share = blahaj::Share::try_from(&data[ID_LEN + 1..]).map_err(|e| anyhow!(e))?;
+
+        Ok(Self {
+            threshold: data[0],
+            id: data[1..=ID_LEN].try_into()?,
+            share,
+        })
+    }
+}
+
+/// Prints the age key of an identity file as `shares` Shamir shares, any
+/// `threshold` of which rebuild it.
+pub fn split(path: &Path, shares: u8, threshold: u8) -> Result<()> {
+    let unlocked = Unlocked::read(path, "`bx identity split`")?;
+    let secret = secret_key(&unlocked)?;
+    let public = &unlocked.recipients()[0];
+
+    for (number, share) in split_secret(&secret, public, shares, threshold)?.iter().enumerate() {
+        println!(
+            "# Share {} of {} of {}, any {} of them rebuild the identity",
+            number + 1,
+            shares,
+            public,
+            threshold
+        );
+        println!("{}\n", share.encode()?);
+    }
+    Ok(())
+}
+
+pub fn split_secret(secret: &[u8], public: &str, shares: u8, threshold: u8) -> Result<Vec<Share>> {
+    if threshold < 2 || threshold > shares {
+        return Err(anyhow!(
+            "The threshold has to be at least 2 and at most the number of shares"
+        ));
+    }
+
+    let id = key_id(public);
+    Ok(Sharks(threshold)
+        .dealer(secret)
+        .take(shares.into())
+        .map(|share| Share { threshold, id, share })
+        .collect())
+}
+
+/// Rebuilds an identity file from shares read from stdin, one per line.
+/// Whitespace in a share and lines starting with `#` are ignored, so the output
+/// of `split` can be used as is.
+pub fn combine(path: &Path, passphrase: bool) -> Result<()> {
+    if path.exists() {
+        return Err(anyhow!("{:?} already exists", path));
+    }
+    if io::stdin().is_terminal() {
+        eprintln!("Enter the shares, one per line:");
+    }
+
+    let (content, public) = combine_shares(io::stdin().lock().lines())?;
+    let passphrase = passphrase.then(file_io::read_new_passphrase).transpose()?;
+    file_io::write_identity(path, &content, passphrase, false)?;
+    println!("Rebuilt {} at {}", public, path.display());
+    Ok(())
+}
+
+/// Reads shares until the threshold is reached. Returns the content of the
+/// identity file and the public key.
+pub fn combine_shares<I>(lines: I) -> Result<(SecretString, String)>
+where
+    I: Iterator<Item = io::Result<String>>,
+{
+    let mut shares: Vec<Share> = Vec::new();
+    for (number, line) in lines.enumerate() {
+        let line: Zeroizing<String> = Zeroizing::new(line?.split_whitespace().collect());
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let share =
+            Share::decode(&line).map_err(|e| anyhow!("line {}: {}", number + 1, e))?;
+        if let Some(first) = shares.first()
+            && (share.id, share.threshold) != (first.id, first.threshold)
+        {
+            return Err(anyhow!(
+                "line {}: share belongs to another key or split",
+                number + 1
+            ));
+        }
+        if shares.iter().all(|other| other.share.x != share.share.x) {
+            shares.push(share);
+        }
+        if shares.len() == usize::from(shares[0].threshold) {
+            break;
+        }
+    }
+
+    let Some(first) = shares.first() else {
+        return Err(anyhow!("No shares given"));
+    };
+    let secret = Sharks(first.threshold)
+        .recover(shares.iter().map(|share| &share.share))
+        .map_err(|_| anyhow!("{} of {} shares given", shares.len(), first.threshold))?;
+    let secret = Zeroizing::new(secret);
+
+    let (content, public) = identity_from_secret(&secret)?;
+    if key_id(&public) != first.id {
+        return Err(anyhow!("The shares don't rebuild the key they were split from"));
+    }
+    Ok((content, public))
+}
+
+fn key_id(public: &str) -> [u8; ID_LEN] {
+    let hash = blake3::hash(public.as_bytes());
+    let mut id = [0; ID_LEN];
+    id.copy_from_slice(&hash.as_bytes()[..ID_LEN]);
+    id
+}
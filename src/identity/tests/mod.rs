@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use age::{
+    secrecy::{ExposeSecret, SecretString},
+    x25519, Encryptor,
+};
+use std::{fs, io, io::Write, os::unix::fs::PermissionsExt, path::Path};
+
+use super::{
+    backup::{parse_paper, render_paper},
+    rotate::{encrypted_files, rotate},
+    secret_key,
+    shamir::{combine_shares, split_secret},
+};
+use crate::file_io::{self, Unlocked};
+
+fn identity(dir: &Path) -> Unlocked {
+    let path = dir.join("jane");
+    let (content, _) = file_io::generate_identity().expect("generates");
+    file_io::write_identity(&path, &content, None, false).expect("writes");
+    Unlocked::read(&path, "test").expect("reads")
+}
+
+/// Encrypts `content` to the identities to `path`.
+fn encrypt_to(path: &Path, content: &str, identities: &[&Unlocked]) {
+    let recipients: Vec<x25519::Recipient> = identities
+        .iter()
+        .flat_map(|identity| identity.recipients())
+        .map(|key| key.parse().expect("age key"))
+        .collect();
+    let encryptor = Encryptor::with_recipients(recipients.iter().map(|r| r as _))
+        .expect("encryptor");
+    let mut encrypted = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut encrypted).expect("writer");
+    writer.write_all(content.as_bytes()).expect("writes");
+    writer.finish().expect("finishes");
+    fs::write(path, encrypted).expect("writes");
+}
+
+fn decrypt(path: &Path, identity: &Unlocked) -> Option<String> {
+    file_io::decrypt_with(path, identity.identities())
+        .expect("reads")
+        .map(|content| String::from_utf8(content.to_vec()).expect("utf-8"))
+}
+
+fn lines(shares: &[String]) -> impl Iterator<Item = io::Result<String>> + '_ {
+    shares.iter().cloned().map(Ok)
+}
+
+#[test]
+fn rewrite_identity() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let path = dir.path().join("jane");
+
+    let (content, public) = file_io::generate_identity().expect("generates");
+    file_io::write_identity(&path, &content, None, false).expect("writes");
+    assert!(file_io::write_identity(&path, &content, None, false).is_err());
+
+    let unlocked = Unlocked::read(&path, "test").expect("reads");
+    assert_eq!(unlocked.recipients(), std::slice::from_ref(&public));
+    assert!(!unlocked.protected);
+    assert!(unlocked.content().expose_secret().starts_with("# public key: "));
+
+    // Replacing goes through a temporary file and keeps the file private.
+    let (content, public) = file_io::generate_identity().expect("generates");
+    file_io::write_identity(&path, &content, None, true).expect("replaces");
+    assert_eq!(Unlocked::read(&path, "test").expect("reads").recipients(), [public]);
+    let mode = fs::metadata(&path).expect("metadata").permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(fs::read_dir(dir.path()).expect("lists").count(), 1);
+
+    // A passphrase wraps the same content in an armored age file.
+    let passphrase = SecretString::from("secret".to_string());
+    file_io::write_identity(&path, &content, Some(passphrase), true).expect("encrypts");
+    let written = fs::read_to_string(&path).expect("reads");
+    assert!(written.starts_with("-----BEGIN AGE ENCRYPTED FILE-----"));
+}
+
+#[test]
+fn names_the_command_for_unsupported_identities() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let path = dir.path().join("yubikey");
+    fs::write(&path, "AGE-PLUGIN-YUBIKEY-1QQQQQQQQQQQQQQQQQQQQQQQ\n").expect("writes");
+
+    let error = super::show_public(&path).expect_err("plugin identity");
+    assert!(error.to_string().starts_with("`bx identity show-public` only supports"), "{}", error);
+}
+
+#[test]
+fn find_encrypted_files() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let root = dir.path();
+    let files = [
+        "keys.age",
+        "hosts/mail.age",
+        "hosts/mail.nix",
+        ".git/keys.age",
+        "generated/keys.age",
+    ];
+    for path in files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().expect("parent")).expect("creates dir");
+        fs::write(path, "").expect("writes");
+    }
+    // Symlinks aren't followed, like a Nix `result` link or a cycle.
+    std::os::unix::fs::symlink(root, root.join("hosts/result")).expect("links");
+    std::os::unix::fs::symlink(root.join("keys.age"), root.join("link.age")).expect("links");
+
+    assert_eq!(
+        encrypted_files(root, &[root.join("generated")]).expect("lists"),
+        vec![root.join("hosts/mail.age"), root.join("keys.age")]
+    );
+}
+
+#[test]
+fn rotate_keeps_the_recipients_of_nested_projects() {
+    let keys = tempfile::tempdir().expect("temp dir");
+    let jane = identity(keys.path());
+    fs::create_dir(keys.path().join("bob")).expect("creates dir");
+    let bob = identity(&keys.path().join("bob"));
+
+    let dir = tempfile::tempdir().expect("temp dir");
+    let root = dir.path().canonicalize().expect("canonical");
+    let sub = root.join("sub");
+    fs::create_dir(&sub).expect("creates dir");
+    let config = format!("version = 1\nidentity = {:?}\n", jane.path);
+    fs::write(root.join("boxednix.toml"), &config).expect("writes");
+    let nested = format!("{}recipients = [{{ key = {:?} }}]\n", config, bob.recipients()[0]);
+    fs::write(sub.join("boxednix.toml"), nested).expect("writes");
+    encrypt_to(&root.join("keys.age"), "root", &[&jane]);
+    encrypt_to(&sub.join("keys.age"), "sub", &[&jane, &bob]);
+
+    rotate(&root, &jane.path).expect("rotates");
+
+    let new = Unlocked::read(&jane.path, "test").expect("reads");
+    assert_ne!(new.recipients(), jane.recipients());
+    assert_eq!(decrypt(&root.join("keys.age"), &new).as_deref(), Some("root"));
+    assert_eq!(decrypt(&root.join("keys.age"), &bob), None);
+    assert_eq!(decrypt(&sub.join("keys.age"), &new).as_deref(), Some("sub"));
+    // Still encrypted to the recipients of the nested project.
+    assert_eq!(decrypt(&sub.join("keys.age"), &bob).as_deref(), Some("sub"));
+    assert_eq!(decrypt(&sub.join("keys.age"), &jane), None);
+}
+
+#[test]
+fn shamir_round_trip() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let unlocked = identity(dir.path());
+    let secret = secret_key(&unlocked).expect("age key");
+    let public = &unlocked.recipients()[0];
+
+    let shares: Vec<String> = split_secret(&secret, public, 5, 3)
+        .expect("splits")
+        .iter()
+        .map(|share| share.encode().expect("encodes"))
+        .collect();
+
+    // Comments, blank lines, whitespace and lower case are accepted, and
+    // reading stops at the threshold.
+    let typed = vec![
+        "# Share 2 of 5".to_string(),
+        shares[1].to_lowercase(),
+        String::new(),
+        shares[4]
+            .chars()
+            .collect::<Vec<_>>()
+            .chunks(4)
+            .map(String::from_iter)
+            .collect::<Vec<_>>()
+            .join(" "),
+        shares[0].clone(),
+        "not read".to_string(),
+    ];
+    let (content, rebuilt) = combine_shares(lines(&typed)).expect("combines");
+    assert_eq!(&rebuilt, public);
+    assert_eq!(content.expose_secret(), unlocked.content().expose_secret());
+
+    let error = combine_shares(lines(&shares[..2])).expect_err("below threshold");
+    assert!(error.to_string().contains("2 of 3"), "{}", error);
+
+    let mut typo = shares[0].clone();
+    typo.replace_range(20..21, if &typo[20..21] == "Q" { "P" } else { "Q" });
+    assert!(combine_shares(lines(&[typo])).is_err());
+
+    fs::create_dir(dir.path().join("other")).expect("creates dir");
+    let other = identity(&dir.path().join("other"));
+    let other_secret = secret_key(&other).expect("age key");
+    let other_share = split_secret(&other_secret, &other.recipients()[0], 3, 3).expect("splits")[0]
+        .encode()
+        .expect("encodes");
+    let error = combine_shares(lines(&[shares[0].clone(), other_share])).expect_err("mixed keys");
+    assert!(error.to_string().contains("another key"), "{}", error);
+
+    assert!(split_secret(&secret, public, 3, 1).is_err());
+    assert!(split_secret(&secret, public, 2, 3).is_err());
+}
+
+#[test]
+fn paper_round_trip() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let unlocked = identity(dir.path());
+    let secret = secret_key(&unlocked).expect("age key");
+    let public = &unlocked.recipients()[0];
+
+    let paper = render_paper(&secret, public).expect("renders");
+    assert!(paper.contains(&format!("# public key: {}", public)));
+    assert!(paper.contains("24. "));
+
+    // The printed backup, QR code included, reads back as is.
+    let (content, rebuilt) = parse_paper(&paper).expect("parses");
+    assert_eq!(&rebuilt, public);
+    assert_eq!(content.expose_secret(), unlocked.content().expose_secret());
+
+    // So do the typed words alone, and the scanned key.
+    let words: Vec<&str> = paper
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(str::split_whitespace)
+        .filter(|token| token.chars().all(|c| c.is_ascii_lowercase()))
+        .collect();
+    assert_eq!(words.len(), 24);
+    let typed = format!("# public key: {}\n{}", public, words.join(" ").to_uppercase());
+    assert_eq!(parse_paper(&typed).expect("parses").1, *public);
+
+    let key = unlocked
+        .content()
+        .expose_secret()
+        .lines()
+        .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+        .expect("age key")
+        .to_string();
+    let scanned = format!("# public key: {}\n{}", public, key);
+    assert_eq!(parse_paper(&scanned).expect("parses").1, *public);
+
+    // Swapped words fail the checksum, and the public key is required.
+    let mut swapped = words.clone();
+    swapped.swap(0, 1);
+    let swapped = format!("# public key: {}\n{}", public, swapped.join(" "));
+    assert!(words[0] == words[1] || parse_paper(&swapped).is_err());
+    assert!(parse_paper(&words.join(" ")).is_err());
+
+    fs::create_dir(dir.path().join("other")).expect("creates dir");
+    let other = identity(&dir.path().join("other"));
+    let wrong = format!("# public key: {}\n{}", other.recipients()[0], key);
+    let error = parse_paper(&wrong).expect_err("other public key");
+    assert!(error.to_string().contains("not its public key"), "{}", error);
+}
@@ -1,111 +1,618 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
+mod agent;
 mod config;
 mod editor;
 mod file_io;
+mod gitignore;
+mod history;
+mod hooks;
+mod identity;
 mod redact;
 mod session;
+mod validate;
 
-use std::path::PathBuf;
-use std::{env, process::Command};
+use anyhow::anyhow;
+use resolve_path::PathResolveExt;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, UNIX_EPOCH};
 
-use editor::EditorContext;
+use editor::{Editor, EditorContext};
+use file_io::SourceChanged;
+use history::History;
+use hooks::Stage;
 use redact::PatternEntry;
-use session::{Session, SessionFile};
+use session::{layout, resolve_conflict, Held, Lock, Session, SessionDir, SessionFile};
+use validate::Choice;
+use zeroize::Zeroizing;
 
 pub use anyhow::{Context, Result};
-pub use log::{debug, error, info};
+pub use log::{debug, error, info, warn};
+
+/// How the sources of a new project are protected.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    /// An identity file.
+    Identity,
+    /// An identity file protected by a passphrase.
+    ProtectedIdentity,
+    /// A passphrase, without identity file.
+    PassphraseOnly,
+}
 
 pub fn create_config(
-    identity: PathBuf,
+    identity: Option<PathBuf>,
     dir: Option<PathBuf>,
-    passphrase: bool,
-    recipients: Vec<PathBuf>,
+    protection: Protection,
+    recipients: Vec<String>,
     recipients_files: Vec<PathBuf>,
+    extends: Option<PathBuf>,
+    ssh: Option<PathBuf>,
 ) -> Result<()> {
     let create_flake = matches!(&dir, Some(dir) if dir.is_absolute()) || dir.is_none();
 
+    let identity = match ssh {
+        Some(key) if !key.try_resolve()?.exists() => {
+            return Err(anyhow!("SSH key {:?} does not exist", key));
+        }
+        Some(key) => Some(key),
+        None => identity.map(offer_ssh_key).transpose()?,
+    };
+
     let cwd = env::current_dir()?;
-    let config = config::create_default(&cwd, identity, dir, recipients, recipients_files)?;
-    if !config.identity.exists() {
-        if let Some(parent) = config.identity.parent() {
-            std::fs::create_dir_all(parent)?;
+    let config = config::create_default(
+        &cwd,
+        identity,
+        dir,
+        recipients,
+        recipients_files,
+        extends,
+        protection == Protection::PassphraseOnly,
+    )?;
+    if protection != Protection::PassphraseOnly {
+        // With several identities, one of them is expected to be missing on each machine.
+        let identity = config.identities.first().context("No identity configured")?;
+        if config.identities.iter().all(|identity| !identity.exists()) {
+            if let Some(parent) = identity.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            let passphrase = protection == Protection::ProtectedIdentity;
+            file_io::create_identity(identity, passphrase)?;
         }
+    }
+
+    let generated_dir = config
+        .generated_dir
+        .context("No generated dir configured")?;
+    let flake = generated_dir.join("flake.nix");
+    if create_flake && !flake.exists() {
+        std::fs::create_dir_all(&generated_dir)?;
+        std::fs::write(flake, include_str!("../assets/default_flake.nix"))?;
+    }
+
+    Ok(())
+}
+
+/// Offers to use an existing SSH key when the identity would have to be generated.
+fn offer_ssh_key(identity: PathBuf) -> Result<PathBuf> {
+    if config::resolve_path(identity.clone())?.exists() || !io::stdin().is_terminal() {
+        return Ok(identity);
+    }
+
+    let Some(key) = file_io::find_ssh_key() else {
+        return Ok(identity);
+    };
 
-        file_io::create_identity(&config.identity, passphrase)?;
+    print!(
+        "No identity at {:?}. Use the SSH key {} instead? [y/N] ",
+        identity,
+        key.display()
+    );
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    match answer.trim() {
+        "y" | "Y" | "yes" => Ok(key),
+        _ => Ok(identity),
+    }
+}
+
+pub fn agent(identities: Vec<PathBuf>, timeout: u64, stop: bool) -> Result<()> {
+    if stop {
+        return agent::stop();
     }
 
-    if create_flake {
-        if let Some(parent) = config.generated_dir.parent() {
-            std::fs::create_dir_all(parent)?;
+    let identities = if identities.is_empty() {
+        config::identities(&env::current_dir()?)?
+    } else {
+        identities
+            .into_iter()
+            .map(config::resolve_path)
+            .collect::<Result<_>>()?
+    };
+
+    agent::serve(identities, Duration::from_secs(timeout * 60))
+}
+
+pub fn show_config() -> Result<()> {
+    let cwd = env::current_dir()?;
+    print!("{}", config::show(&cwd)?);
+    Ok(())
+}
+
+pub fn migrate_config() -> Result<()> {
+    let cwd = env::current_dir()?;
+    for (path, migrated) in config::migrate(&cwd)? {
+        match migrated {
+            Some((version, backup)) => println!(
+                "{}: migrated from version {}, backup at {}",
+                path.display(),
+                version,
+                backup.display()
+            ),
+            None => println!("{}: up to date", path.display()),
         }
+    }
+    Ok(())
+}
 
-        std::fs::write(
-            config.generated_dir.join("flake.nix"),
-            include_str!("../assets/default_flake.nix"),
-        )?;
+pub fn show_public(identity: Option<PathBuf>) -> Result<()> {
+    identity::show_public(&project_identity(identity)?)
+}
+
+pub fn change_passphrase(identity: Option<PathBuf>, remove: bool) -> Result<()> {
+    identity::passphrase(&project_identity(identity)?, remove)
+}
+
+pub fn rotate_identity(identity: Option<PathBuf>) -> Result<()> {
+    let cwd = env::current_dir()?;
+    identity::rotate(&cwd, &project_identity(identity)?)
+}
+
+pub fn split_identity(identity: Option<PathBuf>, shares: u8, threshold: u8) -> Result<()> {
+    identity::split(&project_identity(identity)?, shares, threshold)
+}
+
+pub fn combine_identity(identity: PathBuf, passphrase: bool) -> Result<()> {
+    identity::combine(&config::resolve_path(identity)?, passphrase)
+}
+
+pub fn export_identity(identity: Option<PathBuf>, paper: bool, unprotected: bool) -> Result<()> {
+    identity::export(&project_identity(identity)?, paper, unprotected)
+}
+
+pub fn import_identity(identity: PathBuf, paper: bool, passphrase: bool) -> Result<()> {
+    identity::import(&config::resolve_path(identity)?, paper, passphrase)
+}
+
+/// The given identity, or the first configured one that exists.
+fn project_identity(identity: Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(identity) = identity {
+        return config::resolve_path(identity);
     }
 
+    let identities = config::identities(&env::current_dir()?)?;
+    identities
+        .iter()
+        .find(|identity| identity.exists())
+        .or(identities.first())
+        .cloned()
+        .context("No identity configured")
+}
+
+/// Lists the interrupted sessions, or restores or discards the one with `id`.
+pub fn recover(id: Option<String>, discard: bool) -> Result<()> {
+    clean_up()?;
+    let sessions = session::interrupted()?;
+
+    let Some(id) = id else {
+        if sessions.is_empty() {
+            println!("No interrupted sessions");
+        }
+        for session in sessions {
+            println!(
+                "{}  {}  {} saves",
+                session.id,
+                session.meta.source.display(),
+                session.entries.len()
+            );
+        }
+        return Ok(());
+    };
+
+    let session = sessions
+        .into_iter()
+        .find(|session| session.id == id)
+        .ok_or(anyhow!("No interrupted session {}, see `bx recover`", id))?;
+    if discard {
+        session.discard()?;
+        println!("Discarded session {}", id);
+        return Ok(());
+    }
+
+    let _lock = Lock::acquire(&session.meta.source)?;
+    let source = session.restore()?;
+    println!("Restored the last save of session {} to {}", id, source.display());
     Ok(())
 }
 
-pub fn run(source: PathBuf, editor: &str, editor_args: &[String]) -> Result<()> {
+/// Lists the kept versions of a source, newest first, with the attributes
+/// each one changed. Values are never shown.
+pub fn history(source: PathBuf) -> Result<()> {
+    file_io::protect_process();
     let cwd = env::current_dir()?;
     let config = config::load(&cwd, &source)?;
-    let mut session_file = SessionFile::new(
-        source,
-        PatternEntry::to_module(),
-        config.file_name,
-        config.target_dir,
-        config.identity,
-        config.recipients,
-        config.recipients_files,
-        config.flake_input,
+    let history = History::open(&config.project_root, &source, config.history)?;
+    let versions = history.versions()?;
+    if versions.is_empty() {
+        println!("No earlier versions of {}", source.display());
+        return Ok(());
+    }
+
+    let identities = file_io::unlock_identities(
+        config.identities.iter().map(|path| path.display().to_string()).collect(),
+        config.passphrase_only,
+        &source,
     )?;
+    let outline = |path: &Path| -> Result<_> {
+        let identities = identities.iter().map(|identity| identity.as_ref());
+        match file_io::decrypt_with(path, identities)? {
+            Some(content) => redact::outline(&Zeroizing::new(content)),
+            None => Err(anyhow!("none of the identities can decrypt it")),
+        }
+    };
 
-    if session_file.source_exists() {
-        file_io::decrypt(&mut session_file)?;
-    } else {
-        file_io::write(&mut session_file)?;
+    let modified = fs::metadata(&source)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs());
+    let mut entries: Vec<(String, Option<u64>, &Path)> = versions
+        .iter()
+        .map(|version| (version.number.to_string(), Some(version.saved), version.path.as_path()))
+        .collect();
+    if source.exists() {
+        entries.push(("current".into(), modified, source.as_path()));
+    }
+
+    let outlines: Vec<_> = entries.iter().map(|(_, _, path)| outline(path)).collect();
+    for (index, (name, saved, _)) in entries.iter().enumerate().rev() {
+        let time = saved.map(history::format_time).unwrap_or_default();
+        println!("{:<8} {}", name, time);
+        match (index.checked_sub(1).map(|before| &outlines[before]), &outlines[index]) {
+            (_, Err(e)) => println!("  can't decrypt: {:#}", e),
+            (None, Ok(_)) => println!("  oldest kept version"),
+            (Some(Err(_)), Ok(_)) => println!("  the version before can't be decrypted"),
+            (Some(Ok(before)), Ok(outline)) => {
+                let changes = history::diff(before, outline);
+                if changes.is_empty() {
+                    println!("  no changes to attributes");
+                }
+                for change in changes {
+                    println!("  {}", change);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces a source with one of its kept versions. The replaced source is
+/// kept as a version too, so the restore can be undone.
+pub fn restore(source: PathBuf, number: usize) -> Result<()> {
+    let cwd = env::current_dir()?;
+    let config = config::load(&cwd, &source)?;
+    let history = History::open(&config.project_root, &source, config.history)?;
+    let version = history
+        .version(number)
+        .map_err(|_| anyhow!("No version {} of {:?}, see `bx history`", number, source))?;
+
+    let _lock = Lock::acquire(&source)?;
+    // Read first, as the snapshot may prune the version when the history is full.
+    let content = fs::read(&version.path)?;
+    history.snapshot(&source)?;
+    file_io::replace(&source, &content)?;
+    println!(
+        "Restored version {} of {}, open it with `bx {}` to generate it again",
+        number,
+        source.display(),
+        source.display()
+    );
+    Ok(())
+}
+
+/// Removes the plaintext left behind by crashed sessions.
+fn clean_up() -> Result<()> {
+    let removed = session::clean_up()?;
+    if removed > 0 {
+        eprintln!("Removed the plaintext of {} crashed sessions", removed);
+    }
+    Ok(())
+}
+
+/// A source edited in a session.
+struct Edited {
+    source: PathBuf,
+    target: PathBuf,
+    input: PathBuf,
+    is_new: bool,
+    validate: bool,
+    /// The content decrypted at the start, restored when changes are discarded.
+    original: Zeroizing<Vec<u8>>,
+}
+
+pub fn run(
+    sources: Vec<PathBuf>,
+    identities: Vec<PathBuf>,
+    editor: Option<String>,
+    editor_args: Vec<String>,
+    validate: bool,
+) -> Result<()> {
+    file_io::protect_process();
+    let cwd = env::current_dir()?;
+    let identities = identities
+        .into_iter()
+        .map(config::resolve_path)
+        .collect::<Result<Vec<_>>>()?;
+
+    // Overlapping globs may name a source twice.
+    let mut seen = HashSet::new();
+    let mut unique = Vec::new();
+    for source in sources {
+        if seen.insert(std::path::absolute(&source)?) {
+            unique.push(source);
+        }
+    }
+    let sources = unique;
+
+    let mut configs = Vec::new();
+    for source in &sources {
+        let mut config = config::load(&cwd, source)?;
+        if config.passphrase_only && !identities.is_empty() {
+            return Err(anyhow!("The project is `passphrase_only`, it uses no identities"));
+        }
+        if !identities.is_empty() {
+            config.identities = identities.clone();
+        }
+        gitignore::check(
+            &config.target_dir.join(&config.file_name),
+            source.parent().unwrap_or(Path::new("")),
+            config.gitignore,
+        )?;
+        configs.push(config);
     }
 
-    let editor_input = session_file.input();
-    let session = Session::start(session_file, move |ctx: &mut SessionFile| {
-        let prev_hash = ctx.hash();
+    // The editor settings of the first source apply to all.
+    let first = configs.first().ok_or(anyhow!("No source given"))?;
+    // Arguments given on the command line replace the configured ones.
+    let editor_args = match editor_args.is_empty() {
+        true => first.editor_args.clone(),
+        false => editor_args,
+    };
+    let editor = Editor::resolve(editor.or(first.editor.clone()), editor_args)?;
+
+    // The files keep the layout of their sources in one temp dir, so the
+    // editor shows where each comes from.
+    let dir = SessionDir::create()?;
+    let mut edited: Vec<Edited> = Vec::new();
+    let mut session_files = Vec::new();
+    let mut histories = HashMap::new();
+    let mut locks = Vec::new();
+    let mut is_read_only = false;
+    for ((source, config), relative) in sources.iter().zip(configs).zip(layout(&sources)?) {
+        let validate = validate || config.validate;
+        let target = config.target_dir.join(&config.file_name);
+        let history = History::open(&config.project_root, source, config.history)?;
+        let template = PatternEntry::to_module();
+        let mut session_file =
+            SessionFile::within(&dir, &relative, source.clone(), template, config)?;
+        let input = session_file.input();
+        if let Some(other) = edited.iter().find(|other| other.input == input) {
+            return Err(anyhow!(
+                "{} and {} would both be edited as {}",
+                other.source.display(),
+                source.display(),
+                relative.join(input.file_name().unwrap_or_default()).display()
+            ));
+        }
+
+        let is_new = !session_file.source_exists();
+        match Lock::acquire(source) {
+            Ok(lock) => locks.push(lock),
+            Err(e) => match e.downcast_ref::<Held>() {
+                Some(held) if !is_new && offer_read_only(held, sources.len())? => {
+                    is_read_only = true;
+                }
+                _ => return Err(e),
+            },
+        }
+
+        if !is_new {
+            file_io::decrypt(&mut session_file)?;
+        } else {
+            file_io::write(&mut session_file)?;
+        }
+        histories.insert(source.clone(), history);
+        edited.push(Edited {
+            source: source.clone(),
+            target,
+            original: Zeroizing::new(fs::read(&input)?),
+            input,
+            is_new,
+            validate,
+        });
+        session_files.push(session_file);
+        if is_read_only {
+            break;
+        }
+    }
+
+    if is_read_only {
+        // The sources after the one held by another session are decrypted
+        // read-only as well.
+        for (source, relative) in sources.iter().zip(layout(&sources)?).skip(session_files.len()) {
+            let config = config::load(&cwd, source)?;
+            let mut session_file =
+                SessionFile::within(&dir, &relative, source.clone(), Vec::new(), config)?;
+            if session_file.source_exists() {
+                file_io::decrypt(&mut session_file)?;
+                session_files.push(session_file);
+            }
+        }
+        return read_only(&editor, &session_files);
+    }
+
+    clean_up()?;
+    let interrupted = session::interrupted()?;
+    for session_file in &mut session_files {
+        file_io::resolve_recipients(session_file)?;
+
+        let source = session_file.source();
+        let absolute = std::path::absolute(source)?;
+        if interrupted.iter().any(|session| session.meta.source == absolute) {
+            eprintln!(
+                "warning: an interrupted session of {} can be restored with `bx recover`",
+                source.display()
+            );
+        }
+        session_file.start_journal()?;
+    }
+
+    let inputs: Vec<PathBuf> = edited.iter().map(|file| file.input.clone()).collect();
+    let editing = Arc::new(AtomicBool::new(true));
+    let session_editing = editing.clone();
+    let session = Session::start(session_files, locks, move |ctx: &mut SessionFile| {
         file_io::read(ctx)?;
 
-        if prev_hash == ctx.hash() {
+        // Also retries changes whose encryption failed before.
+        if ctx.is_saved() {
             return Ok(());
         }
+        ctx.record();
 
-        file_io::encrypt(ctx)?;
-        info!("Encrypted changes back to source");
+        hooks::run(ctx, Stage::PreEncrypt)?;
+        if let Some(history) = histories.get(ctx.source())
+            && let Err(e) = history.snapshot(ctx.source())
+        {
+            warn!("Failed to keep the version before the save: {:?}", e);
+            eprintln!("warning: failed to keep the version before the save: {:#}", e);
+        }
+        // A source changed by others is merged once the editor exited, as the
+        // editor has the terminal until then.
+        let source_hash = match file_io::encrypt(ctx) {
+            Err(e) if e.is::<SourceChanged>() && session_editing.load(Ordering::SeqCst) => {
+                return Err(e.context("Not saving, you can merge when the editor exits"));
+            }
+            Err(e) if e.is::<SourceChanged>() => {
+                resolve_conflict(ctx)?;
+                file_io::encrypt(ctx)?
+            }
+            result => result?,
+        };
+        ctx.mark_saved(source_hash);
+        info!("Encrypted changes back to {:?}", ctx.source());
+        hooks::run(ctx, Stage::PostEncrypt)?;
 
         match file_io::generate(ctx) {
             Ok(_) => {
                 info!("Generated file");
-                update_lock_file(ctx.flake_input())?;
+                hooks::run(ctx, Stage::PostGenerate)?;
+            }
+            Err(e) => {
+                error!("File generation stopped because: {:?}", e);
+                match e.downcast_ref::<redact::Problems>() {
+                    Some(problems) => {
+                        eprintln!("warning: file generation stopped:\n\n{}", *problems.rendered)
+                    }
+                    None => eprintln!("warning: file generation stopped: {:#}", e),
+                }
             }
-            Err(e) => error!("File generation stopped because: {:?}", e),
         }
 
         Ok(())
     })?;
 
-    editor::run(editor, editor_input, editor_args)?;
+    loop {
+        editor.run(&inputs)?;
+        let mut problems = 0;
+        for file in edited.iter().filter(|file| file.validate && file.input.exists()) {
+            problems += validate::check(&file.input, &file.source)?;
+        }
+        if problems == 0 {
+            break;
+        }
+
+        match validate::ask()? {
+            Choice::Edit => continue,
+            Choice::Keep => break,
+            Choice::Discard => {
+                // The final sync encrypts and generates the content read at the start.
+                for file in &edited {
+                    fs::write(&file.input, &file.original)?;
+                }
+                editing.store(false, Ordering::SeqCst);
+                session.stop()?;
+                for file in edited.iter().filter(|file| file.is_new) {
+                    remove_discarded(&file.source, &file.target)?;
+                }
+                for file in &edited {
+                    eprintln!("Discarded the changes to {}", file.source.display());
+                }
+                return Ok(());
+            }
+        }
+    }
+    editing.store(false, Ordering::SeqCst);
     session.stop()
 }
 
-fn update_lock_file(flake_input: Option<String>) -> Result<()> {
-    if let Some(input) = flake_input {
-        Command::new("nix")
-            .arg("flake")
-            .arg("update")
-            .arg(&input)
-            // .status()
-            .output()
-            .context(format!("Failed to update flake input '{}'", input))?;
+/// Asks whether to open a source another session edits read-only, along with
+/// the other sources given.
+fn offer_read_only(held: &Held, sources: usize) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+
+    eprintln!("{}", held);
+    match sources {
+        1 => print!("Open it read-only? [y/N] "),
+        _ => print!("Open all {} sources read-only? [y/N] ", sources),
     }
+    io::stdout().flush()?;
 
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Shows the decrypted files without saving changes to them.
+fn read_only(editor: &Editor, session_files: &[SessionFile]) -> Result<()> {
+    let inputs: Vec<PathBuf> = session_files.iter().map(|file| file.input()).collect();
+    for input in &inputs {
+        fs::set_permissions(input, fs::Permissions::from_mode(0o400))?;
+    }
+    editor.run(&inputs)?;
+    eprintln!("Opened read-only, changes were not saved");
+    Ok(())
+}
+
+/// Removes the source and generated file of a discarded new source.
+fn remove_discarded(source: &Path, target: &Path) -> Result<()> {
+    for path in [source, target] {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
     Ok(())
 }
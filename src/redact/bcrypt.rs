@@ -1,9 +1,10 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use anyhow::anyhow;
+use zeroize::Zeroizing;
 
 use super::{
-    model::QuotedString,
+    model::{Params, QuotedString},
     nix::Key,
     replace::{Range, Replace},
     PatternEntry, Result,
@@ -15,14 +16,14 @@ pub struct Bcrypt {
 }
 
 impl Bcrypt {
-    fn hash(key: &str) -> Result<String> {
-        bcrypt::hash(key, bcrypt::DEFAULT_COST).map_err(|e| anyhow!("{:?}", e))
+    fn hash(key: &str, cost: u32) -> Result<String> {
+        bcrypt::hash(key, cost).map_err(|e| anyhow!("{:?}", e))
     }
 }
 
 impl Key for Bcrypt {
-    fn new(key: String, range: Range) -> Result<Option<Self>> {
-        let hash = QuotedString::from(Self::hash(&key)?);
+    fn new(key: Zeroizing<String>, range: Range, params: &Params) -> Result<Option<Self>> {
+        let hash = QuotedString::from(Self::hash(&key, params.bcrypt_cost)?);
 
         Ok(Some(Self { hash, range }))
     }
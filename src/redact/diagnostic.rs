@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fmt;
+use zeroize::Zeroizing;
+
+use super::Range;
+
+/// A problem in a file, rendered like a rustc error.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub range: Range,
+    pub message: String,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(range: Range, message: String) -> Self {
+        Self {
+            range,
+            message,
+            help: None,
+        }
+    }
+
+    pub fn with_help(mut self, help: String) -> Self {
+        self.help = Some(help);
+        self
+    }
+
+    /// Renders the diagnostic with the line it points at and a caret under the
+    /// range:
+    ///
+    /// ```text
+    /// error: `bcrypt` takes a string, found a variable
+    ///  --> keys.age:3:21
+    ///   |
+    /// 3 |   password = bcrypt secret;
+    ///   |                     ^^^^^^
+    /// ```
+    pub fn render(&self, name: &str, text: &str) -> String {
+        let (line, column) = position(text, self.range.start);
+        let source = text.lines().nth(line - 1).unwrap_or_default();
+
+        // The caret ends with the range or the line, and is at least one wide.
+        let start = self.range.start.min(text.len()) - line_start(text, self.range.start);
+        let end = (self.range.end.min(text.len()) - line_start(text, self.range.start))
+            .clamp(start, source.len());
+        let prefix = source.get(..start).unwrap_or_default();
+        let marked = source.get(start..end).unwrap_or_default();
+        let indent: String = prefix
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(marked.chars().count().max(1));
+
+        let gutter = " ".repeat(line.to_string().len());
+        let mut lines = vec![
+            format!("error: {}", self.message),
+            format!("{}--> {}:{}:{}", gutter, name, line, column),
+            format!("{} |", gutter),
+            format!("{} | {}", line, source),
+            format!("{} | {}{}", gutter, indent, carets),
+        ];
+        if let Some(help) = &self.help {
+            lines.push(format!("{} = help: {}", gutter, help));
+        }
+        lines.join("\n") + "\n"
+    }
+}
+
+/// The error of `process` for a file with problems. It only shows where they
+/// are, as `<name>:<line>:<column>: <message>`, so it can be logged. The
+/// rendered lines it points at may hold secrets and are for the terminal.
+pub struct Problems {
+    summary: String,
+    pub rendered: Zeroizing<String>,
+}
+
+impl Problems {
+    pub fn new(name: &str, text: &str, diagnostics: &[Diagnostic]) -> Self {
+        let summary = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let (line, column) = position(text, diagnostic.range.start);
+                format!("{}:{}:{}: {}", name, line, column, diagnostic.message)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        Self {
+            summary,
+            rendered: Zeroizing::new(render(name, text, diagnostics)),
+        }
+    }
+}
+
+impl fmt::Display for Problems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.summary)
+    }
+}
+
+/// Leaves out the rendered lines, like `Display`.
+impl fmt::Debug for Problems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Problems")
+            .field("summary", &self.summary)
+            .finish_non_exhaustive()
+    }
+}
+
+impl std::error::Error for Problems {}
+
+/// Renders diagnostics one after another, with a blank line between them.
+pub fn render(name: &str, text: &str, diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.render(name, text))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The 1-based line and column of a byte offset.
+pub fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+    (line, column)
+}
+
+fn line_start(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1)
+}
@@ -2,6 +2,7 @@
 
 mod bcrypt;
 mod comment;
+mod diagnostic;
 mod model;
 mod nix;
 mod pattern;
@@ -12,12 +13,15 @@ mod sha;
 #[cfg(test)]
 mod tests;
 
-use std::{cmp::Reverse, collections::HashSet};
+use std::{
+    collections::{BTreeMap, HashSet},
+    str::FromStr,
+};
 
 use crate::Result;
 
-use anyhow::anyhow;
 use bcrypt::Bcrypt;
+use blake3::Hash;
 use comment::Comment;
 use model::QuotedString;
 use nix::NodeOrToken;
@@ -25,40 +29,147 @@ use pattern::Pattern;
 use psk::Psk;
 use replace::{Range, Replace};
 use sha::Sha512;
+use zeroize::Zeroizing;
 
+pub(crate) use diagnostic::{render, Diagnostic, Problems};
+pub(crate) use model::Params;
 pub(crate) use pattern::PatternEntry;
 
-pub fn process(content: &[u8]) -> Result<Vec<u8>> {
+/// Finds the first syntax error of a file and misused keywords, like a keyword
+/// applied to something else than a string, whose secret would end up in the
+/// generated file or break it.
+pub fn check(content: &[u8]) -> Result<Vec<Diagnostic>> {
+    let text = std::str::from_utf8(content)?;
+    let mut diagnostics: Vec<Diagnostic> = nix::parse_error(text)
+        .into_iter()
+        .map(|(range, message)| Diagnostic::new(range, message))
+        .collect();
+
+    let mut declared = HashSet::new();
+    let mut used = Vec::new();
+    for node_or_token in nix::parse(text) {
+        let NodeOrToken::Node(node) = node_or_token else {
+            continue;
+        };
+        let name = node.text().to_string();
+        let Ok(entry) = PatternEntry::from_str(&name) else {
+            continue;
+        };
+
+        if nix::parse_pattern(&node).is_some() {
+            declared.insert(name);
+            continue;
+        }
+        if let Some(range) = nix::unapplied_in_list(&node) {
+            let message = format!(
+                "`{}` is not applied in a list, the next string would be generated in plain text",
+                name
+            );
+            let help = format!("wrap both in parentheses: `({} \"...\")`", name);
+            diagnostics.push(Diagnostic::new(range, message).with_help(help));
+            continue;
+        }
+        let Some((range, args)) = nix::applied_args(&node) else {
+            continue;
+        };
+
+        diagnostics.extend(check_args(&name, &entry, range, &args));
+        used.push((name, nix::range(&node)));
+    }
+
+    for (name, range) in used {
+        if declared.contains(&name) {
+            continue;
+        }
+        let message = format!("`{}` is missing in the pattern header", name);
+        let help = format!(
+            "start the file with the keywords it uses, like `{{ {}, ... }}:`",
+            name
+        );
+        diagnostics.push(Diagnostic::new(range, message).with_help(help));
+    }
+
+    diagnostics.sort_by_key(|diagnostic| diagnostic.range.start);
+    Ok(diagnostics)
+}
+
+/// Checks that a keyword is applied to as many plain strings as it takes.
+fn check_args(
+    name: &str,
+    entry: &PatternEntry,
+    range: Range,
+    args: &[nix::SyntaxNode],
+) -> Vec<Diagnostic> {
+    let (arity, usage) = match entry {
+        PatternEntry::Psk => (2, format!("{} \"<ssid>\" \"<passphrase>\"", name)),
+        _ => (1, format!("{} \"<password>\"", name)),
+    };
+    if args.len() < arity {
+        let message = match entry {
+            PatternEntry::Psk => format!("`{}` takes a salt and a key, one is missing", name),
+            _ => format!("`{}` takes a string", name),
+        };
+        return vec![Diagnostic::new(range, message).with_help(format!("write `{}`", usage))];
+    }
+
+    args.iter()
+        .take(arity)
+        .filter_map(|arg| {
+            let message = if !nix::is_string(arg) {
+                format!("`{}` takes a string, found {}", name, nix::describe(arg))
+            } else if nix::has_interpolation(arg) {
+                format!("the string given to `{}` can't contain interpolations", name)
+            } else {
+                return None;
+            };
+            Some(Diagnostic::new(nix::range(arg), message).with_help(format!("write `{}`", usage)))
+        })
+        .collect()
+}
+
+/// The attribute paths of a file with a hash of their values, to tell what
+/// changed between two versions without showing any value.
+pub fn outline(content: &[u8]) -> Result<BTreeMap<String, Hash>> {
+    let text = std::str::from_utf8(content)?;
+    Ok(nix::attributes(text)
+        .into_iter()
+        .map(|(path, value)| (path, blake3::hash(value.as_bytes())))
+        .collect())
+}
+
+/// Replaces the keywords of a file with their results. Fails with `Problems`,
+/// the diagnostics of `check` for the file `name`.
+pub fn process(name: &str, content: &[u8], params: &Params) -> Result<Zeroizing<Vec<u8>>> {
+    let diagnostics = check(content)?;
+    if !diagnostics.is_empty() {
+        let text = Zeroizing::new(String::from_utf8_lossy(content).into_owned());
+        return Err(Problems::new(name, &text, &diagnostics).into());
+    }
+
     let mut pattern = HashSet::new();
     let mut replacments: Vec<Box<dyn Replace>> = Vec::new();
 
     for node_or_token in nix::parse(std::str::from_utf8(content)?) {
         match node_or_token {
             NodeOrToken::Node(node) => {
-                if nix::is_node_error(&node) {
-                    return Err(anyhow!("Syntax Error!"));
-                }
                 if let Some(pat) = nix::parse_pattern(&node) {
                     pattern.insert(pat);
                     continue;
                 }
-                if let Some(bcrypt) = nix::parse_key_for::<Bcrypt>(&node)? {
+                if let Some(bcrypt) = nix::parse_key_for::<Bcrypt>(&node, params)? {
                     replacments.push(Box::new(bcrypt));
                     continue;
                 }
-                if let Some(sha512) = nix::parse_key_for::<Sha512>(&node)? {
+                if let Some(sha512) = nix::parse_key_for::<Sha512>(&node, params)? {
                     replacments.push(Box::new(sha512));
                     continue;
                 }
-                if let Some(psk) = nix::parse_salt_and_key_for::<Psk>(&node)? {
+                if let Some(psk) = nix::parse_salt_and_key_for::<Psk>(&node, params)? {
                     replacments.push(Box::new(psk));
                     continue;
                 }
             }
             NodeOrToken::Token(token) => {
-                if nix::is_token_error(&token) {
-                    return Err(anyhow!("Syntax Error!"));
-                }
                 if let Some(comment) = nix::parse_comment_for::<Comment>(&token) {
                     replacments.push(Box::new(comment));
                     continue;
@@ -75,12 +186,31 @@ pub fn process(content: &[u8]) -> Result<Vec<u8>> {
             .map(|pattern| Box::new(pattern) as Box<_>),
     );
 
-    replacments.sort_by_key(|replace| Reverse(replace.range().start));
+    // A replacement inside another one, like a comment inside a keyword
+    // application, goes with the outer one.
+    replacments.sort_by_key(|replace| replace.range().start);
+    let mut end = 0;
+    replacments.retain(|replace| {
+        let outer = replace.range().start >= end;
+        if outer {
+            end = replace.range().end;
+        }
+        outer
+    });
 
-    let mut output = content.to_vec();
+    // Built in one allocation, as growing it would leave copies behind.
+    let len = replacments.iter().fold(content.len(), |len, replace| {
+        len - replace.range().len() + replace.content().len()
+    });
+    let mut output = Zeroizing::new(Vec::with_capacity(len));
+    let mut end = 0;
     for replace in replacments {
-        output.splice(replace.range(), replace.content().iter().copied());
+        let range = replace.range();
+        output.extend_from_slice(&content[end..range.start]);
+        output.extend_from_slice(replace.content());
+        end = range.end;
     }
+    output.extend_from_slice(&content[end..]);
 
     Ok(output)
 }
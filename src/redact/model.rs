@@ -1,8 +1,24 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use std::ops::Deref;
+use zeroize::Zeroizing;
 
-pub struct QuotedString(String);
+#[derive(Clone, Copy, Debug)]
+pub struct Params {
+    pub bcrypt_cost: u32,
+    pub sha512_rounds: usize,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            sha512_rounds: sha_crypt::ROUNDS_DEFAULT,
+        }
+    }
+}
+
+pub struct QuotedString(Zeroizing<String>);
 
 impl From<&str> for QuotedString {
     fn from(value: &str) -> Self {
@@ -12,6 +28,12 @@ impl From<&str> for QuotedString {
 
 impl From<String> for QuotedString {
     fn from(value: String) -> Self {
+        Self(quoted(&Zeroizing::new(value)))
+    }
+}
+
+impl From<Zeroizing<String>> for QuotedString {
+    fn from(value: Zeroizing<String>) -> Self {
         Self(quoted(&value))
     }
 }
@@ -23,8 +45,8 @@ impl Deref for QuotedString {
     }
 }
 
-fn quoted(value: &str) -> String {
-    let mut buf = String::with_capacity(value.len() + 2);
+fn quoted(value: &str) -> Zeroizing<String> {
+    let mut buf = Zeroizing::new(String::with_capacity(value.len() + 2));
     buf.push('"');
     buf.push_str(value);
     buf.push('"');
@@ -1,18 +1,22 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use rnix::{
+    parser::ParseError,
     Root,
     SyntaxKind::{
-        self, NODE_APPLY, NODE_ERROR, NODE_IDENT, NODE_PAREN, NODE_PATTERN, NODE_PAT_ENTRY,
+        self, NODE_APPLY, NODE_ATTRPATH_VALUE, NODE_ATTR_SET, NODE_ERROR, NODE_IDENT,
+        NODE_INTERPOL, NODE_LIST, NODE_PAREN, NODE_PATTERN, NODE_PAT_ENTRY, NODE_SELECT,
         NODE_STRING, TOKEN_COLON, TOKEN_COMMENT, TOKEN_ELLIPSIS, TOKEN_ERROR, TOKEN_R_BRACE,
         TOKEN_STRING_CONTENT, TOKEN_WHITESPACE,
     },
-    SyntaxNode, SyntaxToken, TextRange, TextSize,
+    SyntaxToken, TextRange, TextSize,
 };
 
-use super::{Range, Result};
+use zeroize::Zeroizing;
 
-pub use rnix::NodeOrToken;
+use super::{Params, Range, Result};
+
+pub use rnix::{NodeOrToken, SyntaxNode};
 
 pub fn parse(nix: &str) -> impl Iterator<Item = NodeOrToken<SyntaxNode, SyntaxToken>> {
     Root::parse(nix)
@@ -26,7 +30,146 @@ pub fn parse(nix: &str) -> impl Iterator<Item = NodeOrToken<SyntaxNode, SyntaxTo
         })
 }
 
-pub fn parse_key_for<K: Key>(node: &SyntaxNode) -> Result<Option<K>> {
+/// The first syntax error of a file, with its range and a message. Later
+/// errors mostly follow from the first one, so they are left out, like nix does.
+pub fn parse_error(nix: &str) -> Option<(Range, String)> {
+    let end = nix.len();
+    Root::parse(nix)
+        .errors()
+        .first()
+        .map(|error| match error {
+            ParseError::Unexpected(range) => (range.to_range(), "unexpected input".into()),
+            ParseError::UnexpectedExtra(range) => (range.to_range(), "unexpected token".into()),
+            ParseError::UnexpectedWanted(got, range, wanted) => (
+                range.to_range(),
+                format!("unexpected {}, expected {}", kind_name(*got), kind_names(wanted)),
+            ),
+            ParseError::UnexpectedDoubleBind(range) => {
+                (range.to_range(), "pattern is bound twice".into())
+            }
+            ParseError::UnexpectedEOF => (end..end, "unexpected end of file".into()),
+            ParseError::UnexpectedEOFWanted(wanted) => (
+                end..end,
+                format!("unexpected end of file, expected {}", kind_names(wanted)),
+            ),
+            ParseError::DuplicatedArgs(range, ident) => {
+                (range.to_range(), format!("argument `{}` is duplicated", ident))
+            }
+            ParseError::RecursionLimitExceeded => (0..end, "nested too deeply".into()),
+            _ => (0..end, error.to_string()),
+        })
+}
+
+/// A readable name of a syntax kind, like `semicolon` for `TOKEN_SEMICOLON`.
+fn kind_name(kind: SyntaxKind) -> String {
+    let name = format!("{:?}", kind);
+    name.trim_start_matches("TOKEN_")
+        .trim_start_matches("NODE_")
+        .replace('_', " ")
+        .to_lowercase()
+}
+
+fn kind_names(kinds: &[SyntaxKind]) -> String {
+    let names: Vec<String> = kinds.iter().copied().map(kind_name).collect();
+    match names.as_slice() {
+        [name] => name.clone(),
+        _ => format!("one of {}", names.join(", ")),
+    }
+}
+
+/// The range of a node followed by a string in a list, like `[ bcrypt "key" ]`.
+/// Nix reads these as two list elements, so the string is not replaced.
+pub fn unapplied_in_list(node: &SyntaxNode) -> Option<Range> {
+    let in_list = node.parent().is_some_and(|parent| parent.kind() == NODE_LIST);
+    let before_string = node
+        .next_sibling()
+        .is_some_and(|sibling| sibling.kind() == NODE_STRING);
+
+    (in_list && before_string).then(|| node.text_range().to_range())
+}
+
+/// The arguments a node is applied to, like `"salt"` and `"key"` for `psk` in
+/// `psk "salt" "key"`, and the range of the whole application.
+pub fn applied_args(node: &SyntaxNode) -> Option<(Range, Vec<SyntaxNode>)> {
+    let mut function = node.clone();
+    let mut args = Vec::new();
+    let mut range = None;
+
+    while let Some(apply) = function.parent().filter(|parent| parent.kind() == NODE_APPLY) {
+        let mut children = apply.children();
+        if children.next().as_ref() != Some(&function) {
+            break;
+        }
+        args.extend(children.next());
+        range = Some(apply.text_range().to_range());
+        function = apply;
+    }
+
+    range.map(|range| (range, args))
+}
+
+/// The range of a node.
+pub fn range(node: &SyntaxNode) -> Range {
+    node.text_range().to_range()
+}
+
+pub fn is_string(node: &SyntaxNode) -> bool {
+    node.kind() == NODE_STRING
+}
+
+pub fn has_interpolation(node: &SyntaxNode) -> bool {
+    node.children().any(|child| child.kind() == NODE_INTERPOL)
+}
+
+/// Describes what a node is, for messages like "found a variable".
+pub fn describe(node: &SyntaxNode) -> String {
+    match node.kind() {
+        NODE_IDENT => "a variable".into(),
+        NODE_SELECT => "an attribute".into(),
+        NODE_PAREN => "parentheses".into(),
+        NODE_ATTR_SET => "an attribute set".into(),
+        NODE_LIST => "a list".into(),
+        kind => format!("a {}", kind_name(kind)),
+    }
+}
+
+/// The attributes of a file with their values, nested attribute sets flattened
+/// into paths like `users.jane.password`. Values are their code without
+/// whitespace and comments.
+pub fn attributes(nix: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    collect_attributes(&Root::parse(nix).syntax(), "", &mut attributes);
+    attributes
+}
+
+fn collect_attributes(node: &SyntaxNode, prefix: &str, attributes: &mut Vec<(String, String)>) {
+    for child in node.children() {
+        if child.kind() != NODE_ATTRPATH_VALUE {
+            collect_attributes(&child, prefix, attributes);
+            continue;
+        }
+
+        let mut parts = child.children();
+        let (Some(attrpath), Some(value)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        let path = format!("{}{}", prefix, code(&attrpath));
+        match value.kind() {
+            NODE_ATTR_SET => collect_attributes(&value, &format!("{}.", path), attributes),
+            _ => attributes.push((path, code(&value))),
+        }
+    }
+}
+
+fn code(node: &SyntaxNode) -> String {
+    node.descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| !matches!(token.kind(), TOKEN_WHITESPACE | TOKEN_COMMENT))
+        .map(|token| token.text().to_string())
+        .collect()
+}
+
+pub fn parse_key_for<K: Key>(node: &SyntaxNode, params: &Params) -> Result<Option<K>> {
     if !K::condition(&node.text().to_string()) {
         return Ok(None);
     }
@@ -48,12 +191,15 @@ pub fn parse_key_for<K: Key>(node: &SyntaxNode) -> Result<Option<K>> {
     }
 
     match (key, range) {
-        (Some(key), Some(range)) => K::new(key, range.to_range()),
+        (Some(key), Some(range)) => K::new(key, range.to_range(), params),
         _ => Ok(None),
     }
 }
 
-pub fn parse_salt_and_key_for<SK: SaltAndKey>(node: &SyntaxNode) -> Result<Option<SK>> {
+pub fn parse_salt_and_key_for<SK: SaltAndKey>(
+    node: &SyntaxNode,
+    params: &Params,
+) -> Result<Option<SK>> {
     if !SK::condition(&node.text().to_string()) {
         return Ok(None);
     }
@@ -79,7 +225,7 @@ pub fn parse_salt_and_key_for<SK: SaltAndKey>(node: &SyntaxNode) -> Result<Optio
     }
 
     match (salt, key, range) {
-        (Some(salt), Some(key), Some(range)) => SK::new(salt, key, range.to_range()),
+        (Some(salt), Some(key), Some(range)) => SK::new(salt, key, range.to_range(), params),
         _ => Ok(None),
     }
 }
@@ -151,15 +297,7 @@ pub fn resolve_pattern_for<P: Position>(node: &SyntaxNode) -> Option<Vec<P>> {
         .collect()
 }
 
-pub fn is_node_error(node: &SyntaxNode) -> bool {
-    node.kind() == NODE_ERROR
-}
-
-pub fn is_token_error(token: &SyntaxToken) -> bool {
-    token.kind() == TOKEN_ERROR
-}
-
-fn parse_key_from_apply(node: &SyntaxNode) -> Option<String> {
+fn parse_key_from_apply(node: &SyntaxNode) -> Option<Zeroizing<String>> {
     let node = node
         .children()
         .find(|children| children.kind() == NODE_STRING)?;
@@ -168,7 +306,7 @@ fn parse_key_from_apply(node: &SyntaxNode) -> Option<String> {
         child_or_token
             .as_token()
             .filter(|token| token.kind() == TOKEN_STRING_CONTENT)
-            .map(|token| token.text().to_owned())
+            .map(|token| Zeroizing::new(token.text().to_owned()))
     })
 }
 
@@ -192,12 +330,17 @@ fn buffered_ancestors(
 }
 
 pub trait Key: Sized {
-    fn new(key: String, range: Range) -> Result<Option<Self>>;
+    fn new(key: Zeroizing<String>, range: Range, params: &Params) -> Result<Option<Self>>;
     fn condition(text: &str) -> bool;
 }
 
 pub trait SaltAndKey: Sized {
-    fn new(salt: String, key: String, range: Range) -> Result<Option<Self>>;
+    fn new(
+        salt: Zeroizing<String>,
+        key: Zeroizing<String>,
+        range: Range,
+        params: &Params,
+    ) -> Result<Option<Self>>;
     fn condition(text: &str) -> bool;
 }
 
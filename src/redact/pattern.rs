@@ -40,7 +40,7 @@ impl Position for Pattern {
     }
 
     fn condition(text: &str) -> bool {
-        PatternEntry::VARIANTS.iter().any(|&entry| entry == text)
+        PatternEntry::VARIANTS.contains(&text)
     }
 }
 
@@ -4,9 +4,10 @@ use anyhow::anyhow;
 use hex::encode;
 use pbkdf2::{hmac::Hmac, pbkdf2};
 use sha1::Sha1;
+use zeroize::Zeroizing;
 
 use super::{
-    model::QuotedString,
+    model::{Params, QuotedString},
     nix::SaltAndKey,
     replace::{Range, Replace},
     PatternEntry, Result,
@@ -18,16 +19,21 @@ pub struct Psk {
 }
 
 impl Psk {
-    fn derive(ssid: &str, key: &str) -> Result<String> {
-        let mut psk = [0u8; 32];
-        pbkdf2::<Hmac<Sha1>>(key.as_bytes(), ssid.as_bytes(), 4096, &mut psk)
+    fn derive(ssid: &str, key: &str) -> Result<Zeroizing<String>> {
+        let mut psk = Zeroizing::new([0u8; 32]);
+        pbkdf2::<Hmac<Sha1>>(key.as_bytes(), ssid.as_bytes(), 4096, &mut psk[..])
             .map_err(|e| anyhow!("{:?}", e))?;
-        Ok(encode(psk))
+        Ok(Zeroizing::new(encode(&psk[..])))
     }
 }
 
 impl SaltAndKey for Psk {
-    fn new(salt: String, key: String, range: Range) -> Result<Option<Self>> {
+    fn new(
+        salt: Zeroizing<String>,
+        key: Zeroizing<String>,
+        range: Range,
+        _: &Params,
+    ) -> Result<Option<Self>> {
         let derivation = QuotedString::from(Self::derive(&salt, &key)?);
 
         Ok(Some(Self { derivation, range }))
@@ -2,8 +2,9 @@
 
 use anyhow::anyhow;
 use sha_crypt::{sha512_simple, Sha512Params};
+use zeroize::Zeroizing;
 
-use super::{nix::Key, PatternEntry, QuotedString, Range, Replace, Result};
+use super::{nix::Key, Params, PatternEntry, QuotedString, Range, Replace, Result};
 
 pub struct Sha512 {
     hash: QuotedString,
@@ -11,15 +12,15 @@ pub struct Sha512 {
 }
 
 impl Sha512 {
-    fn hash(key: &str) -> Result<String> {
-        let params = Sha512Params::default();
+    fn hash(key: &str, rounds: usize) -> Result<String> {
+        let params = Sha512Params::new(rounds).map_err(|e| anyhow!("{:?}", e))?;
         sha512_simple(key, &params).map_err(|e| anyhow!("{:?}", e))
     }
 }
 
 impl Key for Sha512 {
-    fn new(key: String, range: Range) -> Result<Option<Self>> {
-        let hash = QuotedString::from(Self::hash(&key)?);
+    fn new(key: Zeroizing<String>, range: Range, params: &Params) -> Result<Option<Self>> {
+        let hash = QuotedString::from(Self::hash(&key, params.sha512_rounds)?);
 
         Ok(Some(Self { hash, range }))
     }
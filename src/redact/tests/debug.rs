@@ -49,10 +49,10 @@ fn try_inline_node(node: &SyntaxNode, highlights: &[&str]) -> Option<String> {
                 let mut token_str = format!("{:?}", t.kind());
 
                 // Nur annotieren, wenn direkt in NODE_IDENT enthalten
-                if current.kind() == SyntaxKind::NODE_IDENT {
-                    if let Some(label) = highlight_label(t.text(), highlights) {
-                        token_str.push_str(&format!(" -> {:?}", label));
-                    }
+                if current.kind() == SyntaxKind::NODE_IDENT
+                    && let Some(label) = highlight_label(t.text(), highlights)
+                {
+                    token_str.push_str(&format!(" -> {:?}", label));
                 }
 
                 parts.push(format!("{:?}", current.kind()));
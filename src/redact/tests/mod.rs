@@ -6,7 +6,7 @@ use indoc::indoc;
 use std::collections::HashSet;
 use strum::{IntoEnumIterator, VariantNames};
 
-use super::{process, PatternEntry};
+use super::{check, process, render, Params, PatternEntry, Problems};
 
 fn build_nix_test_module() -> (String, HashSet<String>) {
     fn key(name: &str) -> (String, String) {
@@ -79,7 +79,7 @@ fn build_nix_test_module() -> (String, HashSet<String>) {
 fn process_success() {
     let (module, keys) = build_nix_test_module();
 
-    let redacted = process(&module.as_bytes()).expect("should succeed");
+    let redacted = process("test.age", module.as_bytes(), &Params::default()).expect("should succeed");
     let redacted_str = std::str::from_utf8(&redacted).expect("should succeed");
 
     println!("{}", redacted_str);
@@ -89,6 +89,66 @@ fn process_success() {
     }
 }
 
+#[test]
+fn check_finds_problems() {
+    let (module, _) = build_nix_test_module();
+    assert!(check(module.as_bytes()).expect("checks").is_empty());
+
+    let module = indoc! {r#"
+        { bcrypt, psk, ... }: {
+          keys = [ bcrypt "key" ];
+          password = bcrypt secret;
+          wifi = psk "key";
+          hash = sha512 "key";
+          broken = ;
+        }
+    "#};
+    let messages: Vec<_> = check(module.as_bytes())
+        .expect("checks")
+        .into_iter()
+        .map(|diagnostic| diagnostic.message)
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            "`bcrypt` is not applied in a list, the next string would be generated in plain text",
+            "`bcrypt` takes a string, found a variable",
+            "`psk` takes a salt and a key, one is missing",
+            "`sha512` is missing in the pattern header",
+            "unexpected semicolon, expected one of l paren, rec, l brace, l brack, string start, \
+             ident",
+        ]
+    );
+
+    // The error only says where the problems are, as the lines may hold
+    // secrets, and leaves them to the rendered problems.
+    let error = process("test.age", module.as_bytes(), &Params::default()).expect_err("fails");
+    let summary = format!("{:?}", error);
+    assert_eq!(error.to_string().lines().count(), 5, "{}", summary);
+    assert!(error.to_string().starts_with("test.age:"), "{}", summary);
+    assert!(!summary.contains(" | "), "{}", summary);
+    let problems = error.downcast_ref::<Problems>().expect("problems");
+    assert!(problems.rendered.contains(" | "));
+}
+
+#[test]
+fn render_diagnostics() {
+    let module = "{ bcrypt }: {\n  password = bcrypt secret;\n}\n";
+    let diagnostics = check(module.as_bytes()).expect("checks");
+
+    assert_eq!(
+        render("keys.age", module, &diagnostics),
+        indoc! {r#"
+            error: `bcrypt` takes a string, found a variable
+             --> keys.age:2:21
+              |
+            2 |   password = bcrypt secret;
+              |                     ^^^^^^
+              = help: write `bcrypt "<password>"`
+        "#}
+    );
+}
+
 #[test]
 #[ignore = "Just for Debug"]
 fn build_nix_test_module_debug() {
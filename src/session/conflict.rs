@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::{anyhow, Context};
+use blake3::Hash;
+use std::{
+    fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use zeroize::Zeroizing;
+
+use super::SessionFile;
+use crate::{
+    editor::EditorContext,
+    file_io::{self, EncryptionContext, Identities, Recipients},
+    info, warn, Result,
+};
+
+/// Resolves a source that others changed since it was decrypted, once the
+/// editor exited. Merges the changes into the edit, or keeps the edit
+/// encrypted in a side file and leaves the source alone.
+pub fn resolve(ctx: &mut SessionFile) -> Result<()> {
+    let source = ctx.source().to_path_buf();
+    let side = side_path(&source, "mine")?;
+    eprintln!(
+        "warning: {} changed on disk while you edited it, e.g. by a git pull",
+        source.display()
+    );
+
+    if !ask_merge(&side)? {
+        let side_file = SideFile {
+            content: EncryptionContext::input(ctx),
+            output: side.clone(),
+            recipients: EncryptionContext::recipients(ctx),
+            identities: ctx.verifying_identities(),
+        };
+        file_io::encrypt(&side_file)?;
+        return Err(anyhow!(
+            "Kept your edit in {}, {} was not changed",
+            side.display(),
+            source.display()
+        ));
+    }
+
+    let ours = Zeroizing::new(EncryptionContext::input(ctx).to_vec());
+    let base = Zeroizing::new(ctx.base().to_vec());
+    // Decrypts the changed source, which also makes it the base of later saves.
+    file_io::decrypt(ctx)?;
+    let theirs = Zeroizing::new(EncryptionContext::input(ctx).to_vec());
+
+    let path = EditorContext::input(ctx);
+    let (merged, conflicts) = merge(&path, &ours, &base, &theirs)?;
+    fs::write(&path, &merged)?;
+    file_io::read(ctx)?;
+
+    info!("Merged {:?} with {} conflicts", source, conflicts);
+    if conflicts > 0 {
+        eprintln!(
+            "Merged with {} conflicts, resolve the `<<<<<<<` markers with `bx {}`",
+            conflicts,
+            source.display()
+        );
+    } else {
+        eprintln!("Merged the changes to {}", source.display());
+    }
+    Ok(())
+}
+
+/// Asks whether to merge or to keep the edit in the side file. Without a
+/// terminal to ask, the edit is kept.
+fn ask_merge(side: &Path) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+
+    loop {
+        print!(
+            "(m)erge the changes, or (a)bort and keep your edit in {}? [m] ",
+            side.display()
+        );
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(false);
+        }
+        match answer.trim() {
+            "" | "m" | "M" => return Ok(true),
+            "a" | "A" => return Ok(false),
+            _ => continue,
+        }
+    }
+}
+
+/// Merges the changes from `base` to `theirs` into `ours` with
+/// `git merge-file`. The versions are written next to the edited file `path`
+/// for it, and removed afterwards. Returns the result, with conflict markers,
+/// and the number of conflicts.
+pub(super) fn merge(
+    path: &Path,
+    ours: &[u8],
+    base: &[u8],
+    theirs: &[u8],
+) -> Result<(Zeroizing<Vec<u8>>, usize)> {
+    let files = [("mine", ours), ("base", base), ("theirs", theirs)]
+        .map(|(label, content)| (label, path.with_extension(label), content));
+    for (_, file, content) in &files {
+        fs::write(file, content)?;
+    }
+
+    let output = Command::new("git")
+        .arg("merge-file")
+        .arg("-p")
+        .args(files.iter().flat_map(|(label, _, _)| ["-L", label]))
+        .args(files.iter().map(|(_, file, _)| file))
+        .output();
+    for (_, file, _) in &files {
+        if let Err(e) = file_io::wipe(file) {
+            warn!("Failed to remove {:?}: {}", file, e);
+        }
+    }
+
+    let output = output.context("Merging needs git")?;
+    let merged = Zeroizing::new(output.stdout);
+    match output.status.code() {
+        Some(conflicts) if conflicts >= 0 => Ok((merged, conflicts as usize)),
+        _ => Err(anyhow!(
+            "git merge-file failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// A free file next to the source, like `keys.mine.age` for `keys.age` and the
+/// label `mine`, for an edit that can't be saved to it.
+pub(super) fn side_path(source: &Path, label: &str) -> Result<PathBuf> {
+    let stem = source
+        .file_stem()
+        .ok_or(anyhow!("{:?} has no file name", source))?
+        .to_string_lossy();
+
+    let mut path = source.with_file_name(format!("{}.{}.age", stem, label));
+    let mut number = 1;
+    while path.exists() {
+        path = source.with_file_name(format!("{}.{}{}.age", stem, label, number));
+        number += 1;
+    }
+    Ok(path)
+}
+
+/// A copy of an edit, encrypted to another file than the source.
+pub(super) struct SideFile<'a> {
+    pub content: &'a [u8],
+    pub output: PathBuf,
+    pub recipients: &'a Recipients,
+    pub identities: &'a Identities,
+}
+
+impl EncryptionContext for SideFile<'_> {
+    fn input(&self) -> &[u8] {
+        self.content
+    }
+
+    fn output(&self) -> Option<String> {
+        self.output.to_str().map(String::from)
+    }
+
+    fn recipients(&self) -> &Recipients {
+        self.recipients
+    }
+
+    fn verifying_identities(&self) -> &Identities {
+        self.identities
+    }
+
+    fn source_hash(&self) -> Option<Hash> {
+        None
+    }
+}
@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, DirBuilder},
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tempfile::Builder;
+
+use super::{
+    conflict::{side_path, SideFile},
+    lock::{hostname, is_alive, is_gone},
+    session_file::temp_dirs,
+};
+use crate::{
+    config,
+    file_io::{self, Identities, Recipients},
+    info, warn, Result,
+};
+
+const JOURNAL_DIR: &str = "journal";
+const META_FILE: &str = "session.toml";
+
+/// Prefix of the temp dirs of sessions, followed by the pid, so the plaintext
+/// of crashed sessions can be found and removed.
+pub const TEMP_DIR_PREFIX: &str = "boxednix-session-";
+
+/// The journal of a session: every save, encrypted to the recipients of the
+/// source, so the edit survives a crash. It is removed when the session ends
+/// with the last save encrypted to the source.
+pub struct Journal {
+    dir: PathBuf,
+    entries: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Meta {
+    pub source: PathBuf,
+    pub pid: u32,
+    pub host: String,
+    /// Seconds since the Unix epoch.
+    pub started: u64,
+}
+
+/// A session that ended before its last save was encrypted to the source.
+pub struct Interrupted {
+    pub id: String,
+    pub meta: Meta,
+    pub dir: PathBuf,
+    /// The saves, oldest first.
+    pub entries: Vec<PathBuf>,
+}
+
+impl Journal {
+    pub fn create(source: &Path) -> Result<Self> {
+        let meta = Meta {
+            source: std::path::absolute(source)?,
+            pid: std::process::id(),
+            host: hostname(),
+            started: now(),
+        };
+        // A session of several sources keeps a journal for each.
+        let parent = journal_dir()?;
+        DirBuilder::new().recursive(true).mode(0o700).create(&parent)?;
+        let dir = Builder::new()
+            .prefix(&format!("{}-{}-", meta.started, meta.pid))
+            .tempdir_in(&parent)?
+            .keep();
+        fs::write(dir.join(META_FILE), toml::to_string(&meta)?)?;
+
+        info!("Journal of {:?} in {:?}", source, dir);
+        Ok(Self { dir, entries: 0 })
+    }
+
+    /// Adds a save to the journal.
+    pub fn record(
+        &mut self,
+        content: &[u8],
+        recipients: &Recipients,
+        identities: &Identities,
+    ) -> Result<()> {
+        self.entries += 1;
+        let entry = SideFile {
+            content,
+            output: self.dir.join(format!("{:04}.age", self.entries)),
+            recipients,
+            identities,
+        };
+        file_io::encrypt(&entry)?;
+        Ok(())
+    }
+
+    pub fn remove(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+}
+
+impl Interrupted {
+    /// Restores the last save to the source. The source it replaces is kept
+    /// next to it as `<name>.orig.age`, or `<name>.orig1.age` and so on if
+    /// that is taken.
+    pub fn restore(self) -> Result<PathBuf> {
+        let last = self
+            .entries
+            .last()
+            .ok_or(anyhow!("Session {} has no saves to restore", self.id))?;
+
+        let source = &self.meta.source;
+        if source.exists() {
+            fs::copy(source, side_path(source, "orig")?)?;
+        }
+        file_io::replace(source, &fs::read(last)?)?;
+        fs::remove_dir_all(&self.dir)?;
+        Ok(source.clone())
+    }
+
+    pub fn discard(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+}
+
+/// The interrupted sessions of this host, oldest first.
+pub fn interrupted() -> Result<Vec<Interrupted>> {
+    let dir = journal_dir()?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut sessions = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let dir = entry?.path();
+        let Ok(meta) = fs::read_to_string(dir.join(META_FILE)) else {
+            continue;
+        };
+        // A crash while the journal was created can leave its meta truncated,
+        // which must not keep other sessions from starting.
+        let meta: Meta = match toml::from_str(&meta) {
+            Ok(meta) => meta,
+            Err(e) => {
+                eprintln!("warning: skipping the unreadable journal {:?}: {}", dir, e.message());
+                continue;
+            }
+        };
+        if !is_gone(meta.pid, &meta.host) {
+            continue;
+        }
+
+        let mut entries: Vec<PathBuf> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "age"))
+            .collect();
+        entries.sort();
+
+        sessions.push(Interrupted {
+            id: dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            meta,
+            dir,
+            entries,
+        });
+    }
+    sessions.sort_by_key(|session| session.meta.started);
+    Ok(sessions)
+}
+
+/// Removes what crashed sessions left behind: the temp dirs holding their
+/// plaintext, and journals without saves. Returns how many temp dirs were
+/// removed.
+pub fn clean_up() -> Result<usize> {
+    for session in interrupted()? {
+        if session.entries.is_empty() {
+            session.discard()?;
+        }
+    }
+
+    let mut removed = 0;
+    let entries = temp_dirs()
+        .into_iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten();
+    for entry in entries {
+        let path = entry?.path();
+        let pid = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(TEMP_DIR_PREFIX))
+            .and_then(|rest| rest.split('-').next())
+            .and_then(|pid| pid.parse().ok());
+        let Some(pid) = pid else {
+            continue;
+        };
+        if is_alive(pid) {
+            continue;
+        }
+
+        match file_io::wipe_dir(&path) {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("Failed to remove {:?}: {}", path, e),
+        }
+    }
+    Ok(removed)
+}
+
+fn journal_dir() -> Result<PathBuf> {
+    Ok(config::state_dir()?.join(JOURNAL_DIR))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::anyhow;
+use std::{
+    env,
+    fmt::{self, Display},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{info, warn, Result};
+
+/// An advisory lock on a source, held while a session edits it. It is a file
+/// next to the source, `.<name>.lock`, naming the session that holds it, and
+/// is removed when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+}
+
+/// The session holding a lock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner {
+    pub pid: u32,
+    pub host: String,
+    pub user: String,
+    /// Seconds since the Unix epoch.
+    pub since: u64,
+}
+
+/// The error of `Lock::acquire` when another session holds the lock.
+#[derive(Debug)]
+pub struct Held {
+    pub source: PathBuf,
+    pub owner: Owner,
+}
+
+impl Lock {
+    /// Takes the lock on `source`. A lock left behind by a session of this
+    /// host that is gone is taken over. Fails with `Held` if another session
+    /// holds it.
+    pub fn acquire(source: &Path) -> Result<Self> {
+        let path = lock_path(source)?;
+        let owner = Owner::current();
+
+        // Retried once after a stale lock was removed.
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(owner.to_string().as_bytes())?;
+                    info!("Locked {:?}", source);
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(anyhow!("Failed to lock {:?}: {}", source, e)),
+            }
+
+            let held = read_owner(&path);
+            match held {
+                Some(held) if !held.is_stale() => {
+                    return Err(Held {
+                        source: source.to_path_buf(),
+                        owner: held,
+                    }
+                    .into());
+                }
+                // A lock being written right now has no owner yet.
+                None if is_fresh(&path) => {
+                    return Err(anyhow!("{:?} is being locked by another session", source));
+                }
+                stale => {
+                    warn!("Removing the stale lock {:?}", path);
+                    remove_stale(&path, stale.as_ref())?;
+                }
+            }
+        }
+        Err(anyhow!("Failed to lock {:?}", source))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove the lock {:?}: {}", self.path, e);
+        }
+    }
+}
+
+impl Owner {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            host: hostname(),
+            user: env::var("USER").unwrap_or_else(|_| "unknown".into()),
+            since: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let mut owner = Self {
+            pid: 0,
+            host: String::new(),
+            user: String::new(),
+            since: 0,
+        };
+        for line in text.lines() {
+            let (key, value) = line.split_once('=')?;
+            match key {
+                "pid" => owner.pid = value.parse().ok()?,
+                "host" => owner.host = value.into(),
+                "user" => owner.user = value.into(),
+                "since" => owner.since = value.parse().ok()?,
+                _ => {}
+            }
+        }
+        (owner.pid != 0).then_some(owner)
+    }
+
+    /// Whether the session is gone. Only sessions of this host can be checked,
+    /// others are never stale.
+    pub fn is_stale(&self) -> bool {
+        is_gone(self.pid, &self.host)
+    }
+}
+
+impl Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pid={}", self.pid)?;
+        writeln!(f, "host={}", self.host)?;
+        writeln!(f, "user={}", self.user)?;
+        writeln!(f, "since={}", self.since)
+    }
+}
+
+impl Display for Held {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        write!(
+            f,
+            "{:?} is being edited by {} on {} (pid {}) for {} minutes. If that session is gone, remove {:?}",
+            self.source,
+            self.owner.user,
+            self.owner.host,
+            self.owner.pid,
+            now.saturating_sub(self.owner.since) / 60,
+            lock_path(&self.source).unwrap_or_default()
+        )
+    }
+}
+
+impl std::error::Error for Held {}
+
+fn lock_path(source: &Path) -> Result<PathBuf> {
+    let name = source
+        .file_name()
+        .ok_or(anyhow!("{:?} has no file name", source))?;
+    let mut lock_name = std::ffi::OsString::from(".");
+    lock_name.push(name);
+    lock_name.push(".lock");
+    Ok(source.with_file_name(lock_name))
+}
+
+fn read_owner(path: &Path) -> Option<Owner> {
+    fs::read_to_string(path).ok().and_then(|text| Owner::parse(&text))
+}
+
+/// Removes the lock at `path` if it still names the `stale` owner. Another
+/// session may have taken it over since it was read, so it is first moved
+/// aside, which only one session can do, and put back if it turns out to be
+/// a live lock.
+pub(super) fn remove_stale(path: &Path, stale: Option<&Owner>) -> Result<()> {
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(format!(".{}", std::process::id()));
+    let aside = PathBuf::from(aside);
+    if let Err(e) = fs::rename(path, &aside) {
+        // Already removed by another session.
+        return ignore_not_found(e).map_err(Into::into);
+    }
+
+    let moved = read_owner(&aside);
+    if moved.as_ref() != stale || (moved.is_none() && is_fresh(&aside)) {
+        info!("{:?} was taken over by another session, putting it back", path);
+        // Linking fails if yet another session locked meanwhile, which then
+        // holds the lock.
+        let restored = fs::hard_link(&aside, path);
+        fs::remove_file(&aside)?;
+        return match restored {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    fs::remove_file(&aside)?;
+    Ok(())
+}
+
+/// Whether a lock file was created just now, so its owner may still be
+/// written.
+fn is_fresh(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < Duration::from_secs(5))
+}
+
+/// Whether the process `pid` of `host` is gone. Processes of other hosts are
+/// never gone, as they can't be checked.
+pub(super) fn is_gone(pid: u32, host: &str) -> bool {
+    host == hostname() && !is_alive(pid)
+}
+
+pub(super) fn is_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks that the process exists. EPERM means it exists, but
+    // belongs to another user.
+    let exists = unsafe { libc::kill(pid, 0) } == 0;
+    exists || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+pub(super) fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn ignore_not_found(e: io::Error) -> io::Result<()> {
+    match e.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    }
+}
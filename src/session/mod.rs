@@ -1,6 +1,19 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
+mod conflict;
+mod journal;
+mod lock;
+#[allow(clippy::module_inception)]
 mod session;
 mod session_file;
 
-pub(crate) use {session::Session, session_file::SessionFile};
+#[cfg(test)]
+mod tests;
+
+pub(crate) use {
+    conflict::resolve as resolve_conflict,
+    journal::{clean_up, interrupted},
+    lock::{Held, Lock},
+    session::Session,
+    session_file::{layout, SessionDir, SessionFile},
+};
@@ -1,76 +1,251 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use anyhow::anyhow;
-use notify::{
-    self, event::AccessKind, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
-};
+use notify::{self, event::AccessKind, Event, EventKind, PollWatcher, RecursiveMode, Watcher};
 use std::{
-    path::Path,
-    sync::mpsc::{Receiver, Sender},
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
-use crate::{info, Result};
+use crate::{debug, error, info, warn, Result};
+
+use super::{Lock, SessionFile};
 
-use super::SessionFile;
+/// Quiet time after a file event before the file is synced, so an editor that
+/// saves in several steps, like writing a temporary file and renaming it, is
+/// synced once, after the last step.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Interval of the polling watcher used where file events are unavailable.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 pub trait WatcherContext {
     fn target(&self) -> &Path;
     fn target_file(&self) -> &Path;
 }
 
+type OnChange = Box<dyn Fn(&mut SessionFile) -> Result<()> + Send + Sync + 'static>;
+
 pub struct Session {
-    watcher: RecommendedWatcher,
-    handle: JoinHandle<Result<()>>,
+    watcher: Box<dyn Watcher + Send>,
+    handle: JoinHandle<Status>,
+    locks: Vec<Lock>,
+}
+
+/// Outcome of the syncs of a session.
+#[derive(Default)]
+pub struct Status {
+    /// Changes encrypted to the source.
+    pub saves: usize,
+    /// Syncs that failed.
+    pub failures: usize,
+    pub last_error: Option<anyhow::Error>,
+    /// The sources whose last saved content was never encrypted.
+    pub unsaved: Vec<PathBuf>,
 }
 
 impl Session {
+    /// Starts syncing the files, each on its own saves. The session keeps the
+    /// locks on the sources until it stops, so no other session saves to them
+    /// meanwhile.
     pub fn start(
-        session_file: SessionFile,
+        session_files: Vec<SessionFile>,
+        locks: Vec<Lock>,
         on_change: impl Fn(&mut SessionFile) -> Result<()> + Send + Sync + 'static,
     ) -> Result<Self> {
         let (tx, rx) = std::sync::mpsc::channel();
 
         Ok(Self {
-            watcher: Self::watcher(tx, &session_file)?,
-            handle: Self::spawn_event_loop(rx, session_file, Box::new(on_change)),
+            watcher: Self::watcher(tx, &session_files)?,
+            handle: Self::spawn_event_loop(rx, session_files, Box::new(on_change)),
+            locks,
         })
     }
 
+    /// Syncs a file after every save of it. A failed sync is reported and the
+    /// loop goes on, so a later save can fix it. The loop ends when the
+    /// watcher is dropped, and the files are synced once more then, so a save
+    /// the watcher missed is not lost.
     fn spawn_event_loop(
         rx: Receiver<notify::Result<Event>>,
-        mut session_file: SessionFile,
-        on_change: Box<dyn Fn(&mut SessionFile) -> Result<()> + Send + Sync + 'static>,
-    ) -> JoinHandle<Result<()>> {
-        thread::spawn(move || -> Result<()> {
+        mut session_files: Vec<SessionFile>,
+        on_change: OnChange,
+    ) -> JoinHandle<Status> {
+        thread::spawn(move || {
+            let mut status = Status::default();
             while let Ok(event_result) = rx.recv() {
-                let event = &event_result?;
-
-                let is_access_close = matches!(event.kind, EventKind::Access(AccessKind::Close(_)));
-                if !(is_access_close && session_file.exists_in(&event.paths)) {
+                let mut saved = BTreeSet::new();
+                add_saves(event_result, &session_files, &mut saved);
+                if saved.is_empty() {
                     continue;
                 }
 
-                on_change(&mut session_file)?;
+                // Files saved while waiting are synced as well.
+                let open = debounce(&rx, |event_result| {
+                    add_saves(event_result, &session_files, &mut saved)
+                });
+                if !open {
+                    break;
+                }
+                for index in saved {
+                    sync(&mut session_files[index], &on_change, &mut status);
+                }
             }
 
-            Ok(())
+            for session_file in &mut session_files {
+                if session_file.file_exists() {
+                    sync(session_file, &on_change, &mut status);
+                } else {
+                    let path = session_file.target_file();
+                    warn!("{:?} is missing, keeping the last saved state", path);
+                    eprintln!(
+                        "warning: the edited file of {} is missing, keeping the last saved state",
+                        session_file.source().display()
+                    );
+                }
+
+                status.saves += session_file.saves();
+                if session_file.is_saved() {
+                    session_file.close_journal();
+                } else {
+                    status.unsaved.push(session_file.source().to_path_buf());
+                }
+            }
+            status
         })
     }
 
+    /// Watches the directories of the files rather than the files, as editors
+    /// may replace them. Falls back to polling if file events are unavailable,
+    /// e.g. when the inotify limits are reached.
     fn watcher<W: WatcherContext>(
         tx: Sender<notify::Result<Event>>,
-        ctx: &W,
-    ) -> Result<RecommendedWatcher> {
-        let mut watcher = notify::recommended_watcher(tx)?;
-        watcher.watch(ctx.target(), RecursiveMode::NonRecursive)?;
-        info!("Wachting: {:?}", ctx.target_file());
+        ctxs: &[W],
+    ) -> Result<Box<dyn Watcher + Send>> {
+        let dirs: BTreeSet<&Path> = ctxs.iter().map(|ctx| ctx.target()).collect();
+        let watch = |watcher: &mut dyn Watcher| {
+            dirs.iter()
+                .try_for_each(|dir| watcher.watch(dir, RecursiveMode::NonRecursive))
+        };
+
+        let native = notify::recommended_watcher(tx.clone()).and_then(|mut watcher| {
+            watch(&mut watcher)?;
+            Ok(watcher)
+        });
+
+        let watcher: Box<dyn Watcher + Send> = match native {
+            Ok(watcher) => Box::new(watcher),
+            Err(e) => {
+                warn!("File events unavailable, polling instead: {:?}", e);
+                let config = notify::Config::default().with_poll_interval(POLL_INTERVAL);
+                let mut watcher = PollWatcher::new(tx, config)?;
+                watch(&mut watcher)?;
+                Box::new(watcher)
+            }
+        };
+        for ctx in ctxs {
+            info!("Wachting: {:?}", ctx.target_file());
+        }
         Ok(watcher)
     }
 
+    /// Stops watching, waits for the final syncs and releases the locks.
+    /// Prints a summary if a sync failed, and fails if the last saved content
+    /// of a file was never encrypted.
     pub fn stop(self) -> Result<()> {
         drop(self.watcher);
         info!("Watcher stop");
-        self.handle.join().map_err(|e| anyhow!("{:?}", e))?
+        let status = self.handle.join().map_err(|e| anyhow!("{:?}", e))?;
+        drop(self.locks);
+
+        if status.failures > 0 {
+            eprintln!(
+                "{} of {} syncs failed, {} saves encrypted",
+                status.failures,
+                status.failures + status.saves,
+                status.saves
+            );
+        }
+        if status.unsaved.is_empty() {
+            return Ok(());
+        }
+        let message = format!(
+            "The last saved changes to {} were not encrypted",
+            status
+                .unsaved
+                .iter()
+                .map(|source| source.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        match status.last_error {
+            Some(e) => Err(e.context(message)),
+            None => Err(anyhow!(message)),
+        }
+    }
+}
+
+/// Adds the files an event may be a save of to `saved`, by their index.
+fn add_saves(
+    event_result: notify::Result<Event>,
+    session_files: &[SessionFile],
+    saved: &mut BTreeSet<usize>,
+) {
+    match event_result {
+        Ok(event) => saved.extend(
+            session_files
+                .iter()
+                .enumerate()
+                .filter(|(_, session_file)| is_save(&event, session_file))
+                .map(|(index, _)| index),
+        ),
+        Err(e) => warn!("Watcher error: {:?}", e),
+    }
+}
+
+/// Whether the event may be a save of the file. Editors save by writing the
+/// file in place, or by writing another file and renaming or copying it over
+/// the file, so creates, renames and writes all count.
+fn is_save(event: &Event, session_file: &SessionFile) -> bool {
+    let is_write = matches!(
+        event.kind,
+        EventKind::Access(AccessKind::Close(_))
+            | EventKind::Create(_)
+            | EventKind::Modify(_)
+            | EventKind::Any
+    );
+    is_write && session_file.exists_in(&event.paths)
+}
+
+/// Waits until no events arrived for `DEBOUNCE`, passing on the events that
+/// did. Returns false if the watcher was dropped meanwhile.
+fn debounce(
+    rx: &Receiver<notify::Result<Event>>,
+    mut on_event: impl FnMut(notify::Result<Event>),
+) -> bool {
+    loop {
+        match rx.recv_timeout(DEBOUNCE) {
+            Ok(event_result) => on_event(event_result),
+            Err(RecvTimeoutError::Timeout) => return true,
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+}
+
+fn sync(session_file: &mut SessionFile, on_change: &OnChange, status: &mut Status) {
+    // Between the steps of a save through a rename, the file may be missing.
+    if !session_file.file_exists() {
+        debug!("Not syncing, the file is missing");
+        return;
+    }
+
+    if let Err(e) = on_change(session_file) {
+        error!("Sync of {:?} failed: {:?}", session_file.source(), e);
+        eprintln!("error: saving {} failed: {:#}", session_file.source().display(), e);
+        status.failures += 1;
+        status.last_error = Some(e);
     }
 }
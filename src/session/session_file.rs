@@ -1,85 +1,264 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
+use age::secrecy::SecretString;
+use anyhow::anyhow;
 use blake3::Hash;
 use std::{
-    ffi::OsString,
+    env,
+    fs::{self, DirBuilder, Permissions},
+    os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt},
     path::{Path, PathBuf},
+    sync::Arc,
 };
-use tempfile::TempDir;
+use tempfile::{Builder, TempDir};
+use zeroize::Zeroizing;
 
-use super::session::WatcherContext;
+use super::{
+    journal::{Journal, TEMP_DIR_PREFIX},
+    session::WatcherContext,
+};
 use crate::{
+    config::{Config, Hooks, Recipient},
     editor::EditorContext,
     file_io::{
-        DecryptionContext, EncryptionContext, GenerationContext, ReadingContext, WritingContext,
+        self, DecryptionContext, EncryptionContext, GenerationContext, Identities, ReadingContext,
+        RecipientContext, Recipients, WritingContext,
     },
-    redact, Result,
+    hooks::HookContext,
+    redact::{self, Params},
+    warn, Result,
 };
 
+const SHM_DIR: &str = "/dev/shm";
+
+/// The temp dir the decrypted files of a session go to, only the user can
+/// enter it. It is wiped when the last file in it is dropped.
+pub struct SessionDir {
+    dir: TempDir,
+}
+
 pub struct SessionFile {
     path: PathBuf,
-    dir: TempDir,
+    dir: Arc<SessionDir>,
     hash: Option<Hash>,
-    content: Vec<u8>,
+    /// Hash of the content last encrypted to the source, or read from it.
+    saved: Option<Hash>,
+    saves: usize,
+    /// The content last decrypted from or encrypted to the source, the base of
+    /// a merge with changes made by others.
+    base: Zeroizing<Vec<u8>>,
+    /// Hash of the encrypted source as last read or written.
+    source_hash: Option<Hash>,
+    journal: Option<Journal>,
+    content: Zeroizing<Vec<u8>>,
     source: PathBuf,
     target: PathBuf,
-    identity: PathBuf,
-    recipients: Vec<PathBuf>,
-    recipients_files: Vec<PathBuf>,
-    // TODO: maybe just for now
-    flake_input: Option<String>,
+    identities: Vec<PathBuf>,
+    recipients: Vec<Recipient>,
+    resolved_recipients: Recipients,
+    verifying_identities: Identities,
+    passphrase_only: bool,
+    passphrase: Option<SecretString>,
+    project_root: PathBuf,
+    keywords: Params,
+    hooks: Hooks,
 }
 
 impl SessionFile {
-    pub fn new(
+    /// A file in `dir`, decrypted to `relative` below it, so the files of a
+    /// session keep the layout of their sources.
+    pub fn within(
+        dir: &Arc<SessionDir>,
+        relative: &Path,
         source: PathBuf,
         template: Vec<u8>,
-        file_name: OsString,
-        target_dir: PathBuf,
-        identity: PathBuf,
-        recipients: Vec<PathBuf>,
-        recipients_files: Vec<PathBuf>,
-        flake_input: Option<String>,
+        config: Config,
     ) -> Result<Self> {
-        let (path, dir) = Self::create_temp_file(&file_name)?;
-        let target = target_dir.join(file_name);
+        let parent = dir.path().join(relative);
+        DirBuilder::new().recursive(true).mode(0o700).create(&parent)?;
+        let path = parent.join(&config.file_name);
+        let target = config.target_dir.join(config.file_name);
 
         Ok(Self {
             path,
-            dir,
+            dir: dir.clone(),
             hash: None,
-            content: template,
+            saved: None,
+            saves: 0,
+            base: Zeroizing::new(Vec::new()),
+            source_hash: None,
+            journal: None,
+            content: Zeroizing::new(template),
             source,
             target,
-            identity,
-            recipients,
-            recipients_files,
-            flake_input,
+            identities: config.identities,
+            recipients: config.recipients,
+            resolved_recipients: Vec::new(),
+            verifying_identities: Vec::new(),
+            passphrase_only: config.passphrase_only,
+            passphrase: None,
+            project_root: config.project_root,
+            keywords: config.keywords,
+            hooks: config.hooks,
         })
     }
 
-    pub fn hash(&self) -> Option<Hash> {
-        self.hash.to_owned()
+    /// Whether the content read last is the one in the source.
+    pub fn is_saved(&self) -> bool {
+        self.hash == self.saved
+    }
+
+    /// Records that the content read last was encrypted to the source, which
+    /// now has the hash `source_hash`.
+    pub fn mark_saved(&mut self, source_hash: Hash) {
+        self.saved = self.hash;
+        self.base = self.content.clone();
+        self.source_hash = Some(source_hash);
+        self.saves += 1;
+    }
+
+    pub fn base(&self) -> &[u8] {
+        &self.base
+    }
+
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// How often changes were encrypted to the source.
+    pub fn saves(&self) -> usize {
+        self.saves
+    }
+
+    /// Starts journaling the saves of the session.
+    pub fn start_journal(&mut self) -> Result<()> {
+        self.journal = Some(Journal::create(&self.source)?);
+        Ok(())
+    }
+
+    /// Adds the content read last to the journal. A failure is only logged, so
+    /// it doesn't keep the content from being saved to the source.
+    pub fn record(&mut self) {
+        let Some(journal) = self.journal.as_mut() else {
+            return;
+        };
+        let recorded =
+            journal.record(&self.content, &self.resolved_recipients, &self.verifying_identities);
+        if let Err(e) = recorded {
+            warn!("Failed to journal a save: {:?}", e);
+            eprintln!("warning: failed to journal a save: {:#}", e);
+        }
+    }
+
+    /// Removes the journal, once the last save is in the source.
+    pub fn close_journal(&mut self) {
+        if let Some(journal) = self.journal.take()
+            && let Err(e) = journal.remove()
+        {
+            warn!("Failed to remove the journal: {:?}", e);
+        }
     }
 
     pub fn source_exists(&self) -> bool {
         self.source.exists()
     }
 
+    /// Whether the file given to the editor exists.
+    pub fn file_exists(&self) -> bool {
+        self.path.exists()
+    }
+
     pub fn exists_in(&self, other: &[PathBuf]) -> bool {
         other.iter().any(|path| path == &self.path)
     }
+}
+
+impl SessionDir {
+    /// Creates a dir only the user can enter, in memory if possible.
+    pub fn create() -> Result<Arc<Self>> {
+        let prefix = format!("{}{}-", TEMP_DIR_PREFIX, std::process::id());
+        let mut errors = Vec::new();
+        for parent in temp_dirs() {
+            let dir = match Builder::new().prefix(&prefix).tempdir_in(&parent) {
+                Ok(dir) => dir,
+                Err(e) => {
+                    errors.push(format!("{}: {}", parent.display(), e));
+                    continue;
+                }
+            };
+            fs::set_permissions(dir.path(), Permissions::from_mode(0o700))?;
+            if parent == env::temp_dir() {
+                warn!("Decrypting to {:?}, which may be on disk", dir.path());
+                eprintln!(
+                    "warning: no memory backed dir, decrypting to {}, which may be on disk",
+                    parent.display()
+                );
+            }
+            return Ok(Arc::new(Self { dir }));
+        }
+        Err(anyhow!("Can't create a temp dir:\n{}", errors.join("\n")))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
 
-    pub fn flake_input(&self) -> Option<String> {
-        self.flake_input.to_owned()
+impl Drop for SessionDir {
+    /// Overwrites the decrypted files, and whatever the editor left next to
+    /// them, before the temp dir is removed.
+    fn drop(&mut self) {
+        if let Err(e) = file_io::wipe_dir(self.dir.path()) {
+            warn!("Failed to wipe {:?}: {}", self.dir.path(), e);
+        }
     }
+}
 
-    fn create_temp_file(name: &OsString) -> Result<(PathBuf, TempDir)> {
-        let dir = TempDir::new()?;
-        let path = dir.path().join(&name);
+/// The dirs of the sources relative to the dir they all share, e.g. `` and
+/// `hosts` for `a.age` and `hosts/b.age`.
+pub fn layout(sources: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let parents = sources
+        .iter()
+        .map(|source| {
+            let source = std::path::absolute(source)?;
+            Ok(source.parent().unwrap_or(Path::new("/")).to_path_buf())
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-        Ok((path, dir))
+    let mut shared = parents.first().cloned().unwrap_or_default();
+    for parent in &parents {
+        while !parent.starts_with(&shared) {
+            shared.pop();
+        }
     }
+    Ok(parents
+        .iter()
+        .map(|parent| parent.strip_prefix(&shared).unwrap_or(parent).to_path_buf())
+        .collect())
+}
+
+/// The dirs the decrypted files go to, best first: `$XDG_RUNTIME_DIR` and
+/// `/dev/shm` are kept in memory, the temp dir may be on disk.
+pub(super) fn temp_dirs() -> Vec<PathBuf> {
+    let runtime = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| is_own_dir(dir));
+    let shm = Some(PathBuf::from(SHM_DIR)).filter(|dir| dir.is_dir());
+
+    let mut dirs: Vec<PathBuf> = runtime.into_iter().chain(shm).collect();
+    dirs.push(env::temp_dir());
+    dirs.dedup();
+    dirs
+}
+
+/// Whether `dir` is a dir of the user that others can't enter, as
+/// `$XDG_RUNTIME_DIR` must be.
+fn is_own_dir(dir: &Path) -> bool {
+    fs::metadata(dir).is_ok_and(|metadata| {
+        metadata.is_dir()
+            && metadata.uid() == unsafe { libc::getuid() }
+            && metadata.mode() & 0o077 == 0
+    })
 }
 
 impl ReadingContext for SessionFile {
@@ -87,7 +266,7 @@ impl ReadingContext for SessionFile {
         self.path.to_str().map(String::from)
     }
 
-    fn output(&mut self, content: Vec<u8>, hash: Hash) {
+    fn output(&mut self, content: Zeroizing<Vec<u8>>, hash: Hash) {
         self.content = content;
         self.hash = Some(hash);
     }
@@ -104,6 +283,7 @@ impl WritingContext for SessionFile {
 
     fn result(&mut self, hash: Hash) {
         self.hash = Some(hash);
+        self.saved = Some(hash);
     }
 }
 
@@ -117,14 +297,26 @@ impl DecryptionContext for SessionFile {
     }
 
     fn identities(&self) -> Vec<String> {
-        match self.identity.to_str().map(String::from) {
-            Some(identites) => vec![identites],
-            None => vec![],
-        }
+        self.identities
+            .iter()
+            .filter_map(|path| path.to_str().map(String::from))
+            .collect()
     }
 
-    fn result(&mut self, hash: Hash) {
+    fn passphrase_only(&self) -> bool {
+        self.passphrase_only
+    }
+
+    fn unlocked(&mut self, passphrase: SecretString) {
+        self.passphrase = Some(passphrase);
+    }
+
+    fn result(&mut self, content: Zeroizing<Vec<u8>>, hash: Hash, source_hash: Hash) {
+        self.base = content.clone();
+        self.content = content;
         self.hash = Some(hash);
+        self.saved = Some(hash);
+        self.source_hash = Some(source_hash);
     }
 }
 
@@ -137,31 +329,64 @@ impl EncryptionContext for SessionFile {
         self.source.to_str().map(String::from)
     }
 
+    fn recipients(&self) -> &Recipients {
+        &self.resolved_recipients
+    }
+
+    fn verifying_identities(&self) -> &Identities {
+        &self.verifying_identities
+    }
+
+    fn source_hash(&self) -> Option<Hash> {
+        self.source_hash
+    }
+}
+
+impl RecipientContext for SessionFile {
     fn identities(&self) -> Vec<String> {
-        match self.identity.to_str().map(String::from) {
-            Some(identites) => vec![identites],
-            None => vec![],
-        }
+        self.identities
+            .iter()
+            .filter_map(|path| path.to_str().map(String::from))
+            .collect()
     }
 
     fn recipients(&self) -> Vec<String> {
         self.recipients
             .iter()
-            .filter_map(|path| path.to_str().map(String::from))
+            .filter_map(|recipient| match recipient {
+                Recipient::Key(key) => Some(key.clone()),
+                Recipient::File(_) => None,
+            })
             .collect()
     }
 
     fn recipients_files(&self) -> Vec<String> {
-        self.recipients_files
+        self.recipients
             .iter()
-            .filter_map(|path| path.to_str().map(String::from))
+            .filter_map(|recipient| match recipient {
+                Recipient::File(path) => path.to_str().map(String::from),
+                Recipient::Key(_) => None,
+            })
             .collect()
     }
+
+    fn passphrase_only(&self) -> bool {
+        self.passphrase_only
+    }
+
+    fn passphrase(&self) -> Option<SecretString> {
+        self.passphrase.clone()
+    }
+
+    fn result(&mut self, recipients: Recipients, identities: Identities) {
+        self.resolved_recipients = recipients;
+        self.verifying_identities = identities;
+    }
 }
 
 impl GenerationContext for SessionFile {
-    fn input(&self) -> Result<Vec<u8>> {
-        redact::process(&self.content)
+    fn input(&self) -> Result<Zeroizing<Vec<u8>>> {
+        redact::process(&self.source.display().to_string(), &self.content, &self.keywords)
     }
 
     fn output(&self) -> Option<String> {
@@ -175,9 +400,27 @@ impl EditorContext for SessionFile {
     }
 }
 
+impl HookContext for SessionFile {
+    fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    fn source(&self) -> &Path {
+        &self.source
+    }
+
+    fn target(&self) -> &Path {
+        &self.target
+    }
+
+    fn project_root(&self) -> &Path {
+        &self.project_root
+    }
+}
+
 impl WatcherContext for SessionFile {
     fn target(&self) -> &Path {
-        &self.dir.path()
+        self.path.parent().unwrap_or(self.dir.path())
     }
 
     fn target_file(&self) -> &Path {
@@ -0,0 +1,309 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use anyhow::anyhow;
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+    time::Duration,
+};
+
+use super::{
+    conflict,
+    journal::{Interrupted, Meta},
+    layout,
+    lock::{self, Owner},
+    Held, Lock, Session, SessionDir, SessionFile,
+};
+use crate::{
+    config::Config,
+    editor::EditorContext,
+    file_io::{self, EncryptionContext},
+    redact::Params,
+};
+
+fn config(dir: &Path, file_name: &str) -> Config {
+    Config {
+        identities: vec![],
+        recipients: vec![],
+        passphrase_only: false,
+        file_name: file_name.into(),
+        target_dir: dir.to_path_buf(),
+        project_root: dir.to_path_buf(),
+        editor: None,
+        editor_args: vec![],
+        validate: false,
+        history: 0,
+        keywords: Params::default(),
+        hooks: Default::default(),
+        gitignore: Default::default(),
+    }
+}
+
+fn session_file(dir: &Path) -> SessionFile {
+    let session_dir = SessionDir::create().expect("session dir");
+    let source = dir.join("keys.age");
+    let config = config(dir, "keys.nix");
+    let mut session_file =
+        SessionFile::within(&session_dir, Path::new(""), source, b"{ }".to_vec(), config)
+            .expect("session file");
+    file_io::write(&mut session_file).expect("writes");
+    session_file
+}
+
+fn next(rx: &Receiver<Vec<u8>>) -> Vec<u8> {
+    rx.recv_timeout(Duration::from_secs(5)).expect("synced")
+}
+
+#[test]
+fn saves_in_place_and_through_rename() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let session_file = session_file(dir.path());
+    let path = EditorContext::input(&session_file);
+
+    let (tx, rx) = mpsc::channel();
+    let lock = Lock::acquire(&dir.path().join("keys.age")).expect("locks");
+    let session = Session::start(vec![session_file], vec![lock], move |ctx: &mut SessionFile| {
+        file_io::read(ctx)?;
+        if !ctx.is_saved() {
+            tx.send(EncryptionContext::input(ctx).to_vec())?;
+            ctx.mark_saved(blake3::hash(b""));
+        }
+        Ok(())
+    })
+    .expect("starts");
+
+    // Like vim with `backupcopy=no` or a JetBrains safe write.
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, "{ a = 1; }").expect("writes");
+    fs::rename(&temp, &path).expect("renames");
+    assert_eq!(next(&rx), b"{ a = 1; }");
+
+    fs::write(&path, "{ a = 2; }").expect("writes");
+    assert_eq!(next(&rx), b"{ a = 2; }");
+
+    // A save right before the editor exits is synced when the session stops.
+    fs::write(&path, "{ a = 3; }").expect("writes");
+    session.stop().expect("stops");
+    assert_eq!(rx.try_iter().last().expect("synced"), b"{ a = 3; }");
+}
+
+#[test]
+fn syncs_each_file_on_its_own_save() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let sources = [dir.path().join("keys.age"), dir.path().join("hosts/web.age")];
+    let relatives = layout(&sources).expect("lays out");
+    assert_eq!(relatives, [PathBuf::new(), PathBuf::from("hosts")]);
+
+    let session_dir = SessionDir::create().expect("session dir");
+    let mut session_files = Vec::new();
+    let mut locks = Vec::new();
+    for ((source, relative), name) in sources.iter().zip(&relatives).zip(["keys", "web"]) {
+        fs::create_dir_all(source.parent().expect("parent")).expect("creates");
+        let config = config(source.parent().expect("parent"), &format!("{}.nix", name));
+        let mut session_file =
+            SessionFile::within(&session_dir, relative, source.clone(), b"{ }".to_vec(), config)
+                .expect("session file");
+        file_io::write(&mut session_file).expect("writes");
+        session_files.push(session_file);
+        locks.push(Lock::acquire(source).expect("locks"));
+    }
+    let keys = EditorContext::input(&session_files[0]);
+    let web = EditorContext::input(&session_files[1]);
+    assert_eq!(keys, session_dir.path().join("keys.nix"));
+    assert_eq!(web, session_dir.path().join("hosts/web.nix"));
+    drop(session_dir);
+
+    let (tx, rx) = mpsc::channel();
+    let session = Session::start(session_files, locks, move |ctx: &mut SessionFile| {
+        file_io::read(ctx)?;
+        if !ctx.is_saved() {
+            let name = ctx.source().file_name().expect("name").to_string_lossy().into_owned();
+            tx.send((name, EncryptionContext::input(ctx).to_vec()))?;
+            ctx.mark_saved(blake3::hash(b""));
+        }
+        Ok(())
+    })
+    .expect("starts");
+
+    fs::write(&web, "{ a = 1; }").expect("writes");
+    let synced = rx.recv_timeout(Duration::from_secs(5)).expect("synced");
+    assert_eq!(synced, ("web.age".to_string(), b"{ a = 1; }".to_vec()));
+
+    fs::write(&keys, "{ b = 2; }").expect("writes");
+    fs::write(&web, "{ a = 3; }").expect("writes");
+    let mut synced: Vec<_> = (0..2)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).expect("synced"))
+        .collect();
+    synced.sort();
+    assert_eq!(
+        synced,
+        [
+            ("keys.age".to_string(), b"{ b = 2; }".to_vec()),
+            ("web.age".to_string(), b"{ a = 3; }".to_vec()),
+        ]
+    );
+    session.stop().expect("stops");
+    assert!(rx.try_recv().is_err());
+    assert!(!keys.parent().expect("temp dir").exists());
+}
+
+#[test]
+fn keeps_syncing_after_failed_saves() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let session_file = session_file(dir.path());
+    let path = EditorContext::input(&session_file);
+
+    let (tx, rx) = mpsc::channel();
+    let lock = Lock::acquire(&dir.path().join("keys.age")).expect("locks");
+    let session = Session::start(vec![session_file], vec![lock], move |ctx: &mut SessionFile| {
+        file_io::read(ctx)?;
+        if ctx.is_saved() {
+            return Ok(());
+        }
+        let content = EncryptionContext::input(ctx).to_vec();
+        tx.send(content.clone())?;
+        if content == b"broken" {
+            return Err(anyhow!("cannot encrypt"));
+        }
+        ctx.mark_saved(blake3::hash(b""));
+        Ok(())
+    })
+    .expect("starts");
+
+    fs::write(&path, "broken").expect("writes");
+    assert_eq!(next(&rx), b"broken");
+
+    fs::write(&path, "{ a = 1; }").expect("writes");
+    assert_eq!(next(&rx), b"{ a = 1; }");
+    session.stop().expect("last save was encrypted");
+}
+
+#[test]
+fn fails_when_the_last_save_was_not_encrypted() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let session_file = session_file(dir.path());
+    let path = EditorContext::input(&session_file);
+
+    let lock = Lock::acquire(&dir.path().join("keys.age")).expect("locks");
+    let session = Session::start(vec![session_file], vec![lock], |ctx: &mut SessionFile| {
+        file_io::read(ctx)?;
+        if ctx.is_saved() {
+            return Ok(());
+        }
+        Err(anyhow!("cannot encrypt"))
+    })
+    .expect("starts");
+
+    fs::write(&path, "{ a = 1; }").expect("writes");
+    let error = session.stop().expect_err("fails");
+    assert!(format!("{:#}", error).contains("cannot encrypt"));
+}
+
+#[test]
+fn locks_against_other_sessions() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let source = dir.path().join("keys.age");
+    let lock_file = dir.path().join(".keys.age.lock");
+
+    let lock = Lock::acquire(&source).expect("locks");
+    let error = Lock::acquire(&source).expect_err("is held");
+    let held = error.downcast_ref::<Held>().expect("held");
+    assert_eq!(held.owner.pid, std::process::id());
+
+    // A lock of a session that is gone is taken over.
+    let owner = fs::read_to_string(&lock_file).expect("reads");
+    drop(lock);
+    assert!(!lock_file.exists());
+
+    let mut child = std::process::Command::new("true").spawn().expect("spawns");
+    child.wait().expect("exits");
+    let stale = owner.replace(
+        &format!("pid={}", std::process::id()),
+        &format!("pid={}", child.id()),
+    );
+    fs::write(&lock_file, stale).expect("writes");
+    let lock = Lock::acquire(&source).expect("takes over");
+
+    // A session that read the stale owner before it was taken over leaves
+    // the new lock alone.
+    let taken = fs::read_to_string(&lock_file).expect("reads");
+    let gone = Owner {
+        pid: child.id(),
+        host: lock::hostname(),
+        user: "someone".into(),
+        since: 0,
+    };
+    lock::remove_stale(&lock_file, Some(&gone)).expect("leaves it");
+    assert_eq!(fs::read_to_string(&lock_file).expect("still locked"), taken);
+    assert_eq!(fs::read_dir(dir.path()).expect("lists").count(), 1);
+    drop(lock);
+    assert!(!lock_file.exists());
+}
+
+#[test]
+fn merges_changes_of_others() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let path = dir.path().join("keys.nix");
+    let base = b"{\n  a = 1;\n  b = 2;\n  c = 3;\n}\n";
+    let ours = b"{\n  a = 10;\n  b = 2;\n  c = 3;\n}\n";
+    let theirs = b"{\n  a = 1;\n  b = 2;\n  c = 30;\n}\n";
+
+    let (merged, conflicts) = conflict::merge(&path, ours, base, theirs).expect("merges");
+    assert_eq!(conflicts, 0);
+    assert_eq!(*merged, b"{\n  a = 10;\n  b = 2;\n  c = 30;\n}\n");
+
+    let other = b"{\n  a = 5;\n  b = 2;\n  c = 3;\n}\n";
+    let (merged, conflicts) = conflict::merge(&path, ours, base, other).expect("merges");
+    assert_eq!(conflicts, 1);
+    assert!(String::from_utf8(merged.to_vec()).expect("utf-8").contains("<<<<<<< mine"));
+    assert_eq!(fs::read_dir(dir.path()).expect("lists").count(), 0);
+}
+
+#[test]
+fn restores_interrupted_sessions() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let source = dir.path().join("keys.age");
+    let journal = dir.path().join("journal");
+    fs::create_dir(&journal).expect("creates");
+    fs::write(&source, "source").expect("writes");
+    // The original of an earlier restore is kept.
+    fs::write(dir.path().join("keys.orig.age"), "older").expect("writes");
+    for (entry, content) in [("0001.age", "first"), ("0002.age", "second")] {
+        fs::write(journal.join(entry), content).expect("writes");
+    }
+
+    let session = Interrupted {
+        id: "1-1".into(),
+        meta: Meta {
+            source: source.clone(),
+            pid: 1,
+            host: String::new(),
+            started: 1,
+        },
+        dir: journal.clone(),
+        entries: vec![journal.join("0001.age"), journal.join("0002.age")],
+    };
+    assert_eq!(session.restore().expect("restores"), source);
+    assert_eq!(fs::read_to_string(&source).expect("reads"), "second");
+    let orig = dir.path().join("keys.orig.age");
+    assert_eq!(fs::read_to_string(orig).expect("reads"), "older");
+    let orig = dir.path().join("keys.orig1.age");
+    assert_eq!(fs::read_to_string(orig).expect("reads"), "source");
+    assert!(!journal.exists());
+}
+
+#[test]
+fn wipes_the_plaintext_when_dropped() {
+    let dir = tempfile::tempdir().expect("temp dir");
+    let session_file = session_file(dir.path());
+    let path = EditorContext::input(&session_file);
+    let temp_dir = path.parent().expect("temp dir").to_path_buf();
+    assert!(path.exists());
+    let mode = fs::metadata(&path).expect("metadata").permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    drop(session_file);
+    assert!(!temp_dir.exists());
+}
@@ -0,0 +1,54 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    fs,
+    io::{self, IsTerminal, Write},
+    path::Path,
+};
+use zeroize::Zeroizing;
+
+use crate::{redact, Result};
+
+/// What to do with a file that has problems.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Choice {
+    Edit,
+    Discard,
+    Keep,
+}
+
+/// Checks the edited file, like `visudo`. Prints its problems, named after the
+/// source, and returns how many there are.
+pub fn check(path: &Path, name: &Path) -> Result<usize> {
+    let content = Zeroizing::new(fs::read(path)?);
+    let diagnostics = redact::check(&content)?;
+    if !diagnostics.is_empty() {
+        let text = Zeroizing::new(String::from_utf8_lossy(&content).into_owned());
+        eprintln!("{}", redact::render(&name.display().to_string(), &text, &diagnostics));
+    }
+    Ok(diagnostics.len())
+}
+
+/// Asks what to do with a file that has problems. Without a terminal to ask,
+/// the file is kept.
+pub fn ask() -> Result<Choice> {
+    if !io::stdin().is_terminal() {
+        return Ok(Choice::Keep);
+    }
+
+    loop {
+        print!("What now? (e)dit again, (d)iscard changes, (k)eep anyway [e] ");
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(Choice::Keep);
+        }
+        match answer.trim() {
+            "" | "e" | "E" => return Ok(Choice::Edit),
+            "d" | "D" => return Ok(Choice::Discard),
+            "k" | "K" => return Ok(Choice::Keep),
+            _ => continue,
+        }
+    }
+}